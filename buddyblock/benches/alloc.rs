@@ -0,0 +1,44 @@
+// compares the allocation through the free lists with the bitmap scan it replaced:
+// cargo bench --features bench
+use std::time::{Duration, Instant};
+use buddyblock::{BuddyBlock, UNIT_SIZE};
+
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+struct Page {
+    buf: [u64; 512]
+}
+
+// fill the allocator with single units, free every other one from the end, and time allocating
+// them again, which makes the scan walk the whole bitmap of the first level
+fn run(pages: usize, alloc: fn(&mut BuddyBlock<'static>, usize) -> Option<usize>) -> (Duration, usize) {
+    let mem: Vec<Page> = vec![Page { buf: [0; 512] }; pages];
+    let mut buddy = unsafe {
+        BuddyBlock::new(mem.as_ptr() as usize, mem.len() * size_of::<Page>())
+    };
+
+    let mut addrs = Vec::new();
+    while let Some(addr) = buddy.alloc(UNIT_SIZE) {
+        addrs.push(addr);
+    }
+    addrs.sort();
+    let freed: Vec<_> = addrs.iter().rev().step_by(2).copied().collect();
+    for &addr in &freed {
+        buddy.dealloc(addr, UNIT_SIZE);
+    }
+
+    let start = Instant::now();
+    for _ in 0..freed.len() {
+        alloc(&mut buddy, UNIT_SIZE).unwrap();
+    }
+    (start.elapsed(), freed.len())
+}
+
+fn main() {
+    println!("{:>10} {:>10} {:>14} {:>14}", "memory", "allocs", "scan", "free lists");
+    for pages in [0x1000, 0x4000, 0x10000] {
+        let (scan, count) = run(pages, BuddyBlock::alloc_by_scan);
+        let (lists, _) = run(pages, BuddyBlock::alloc);
+        println!("{:>8}MB {:>10} {:>14?} {:>14?}", (pages * 4096) >> 20, count, scan, lists);
+    }
+}
@@ -5,12 +5,61 @@ use core::mem::{size_of, MaybeUninit, transmute};
 use core::slice::from_raw_parts_mut;
 use num_integer::div_ceil;
 
+mod zone;
+
+pub use zone::{BuddyZones, MAX_RANGES, ZONES, Zone, ZoneRange};
+
 pub const UNIT_SIZE: usize = 4096;
 
+// a level per bit of the unit count at most
+const MAX_LEVELS: usize = usize::BITS as usize;
+
+// the memory handed out or reserved is never written: everything the allocator keeps, including
+// the free lists, is in the metadata before the data, so `reserve` preserves the content of the
+// range, and `add_free_range` does not touch it either.
 pub struct BuddyBlock<'a> {
     info: BuddyBlockInfo,
     used: usize,
     bitmaps: &'a mut [BlockBitmap],
+    // links of the free block starting at each unit
+    links: &'a mut [FreeBlock],
+    // level + 1 of the block allocated by `alloc` at each unit, or 0, so that frees with a
+    // wrong length are caught
+    orders: &'a mut [u8],
+    // address of the first free block of each level, or 0. the blocks whose bit is set are
+    // linked through `links`, so that `alloc` finds one without scanning the bitmaps.
+    free_lists: [usize; MAX_LEVELS],
+    // see `set_alloc_fault`
+    alloc_fault: Option<fn(usize) -> bool>,
+    alloc_calls: usize,
+}
+
+// inconsistency found by `BuddyBlock::check_invariants`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuddyCorruption {
+    // (level, block index) of a free block inside a free ancestor
+    FreeParent(u32, usize),
+    // (level, block index) of a free block whose buddy is free, which were not merged
+    Unmerged(u32, usize),
+    // level whose count differs from its bits
+    BadCount(u32),
+    // level whose free list differs from its bits
+    BadFreeList(u32),
+    // `used`, and the bytes of the blocks that are not free
+    BadUsed(usize, usize),
+}
+
+// reason of a failed `BuddyBlock::try_dealloc`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeallocError {
+    // the range is not in the data
+    OutOfRange,
+    // no block allocated by `alloc` starts at the address
+    Misaligned,
+    // the block or a block containing it is free
+    DoubleFree,
+    // the length is not the one of the allocation, which is given rounded to its block
+    SizeMismatch(usize),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -34,6 +83,14 @@ struct BlockBitmapRef<'a> {
     count: &'a mut usize,
 }
 
+// links of a free block to its neighbors in the free list of its level, as addresses or 0
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FreeBlock {
+    next: usize,
+    prev: usize,
+}
+
 unsafe impl Send for BlockBitmap {}
 
 impl BuddyBlockInfo {
@@ -93,7 +150,9 @@ impl BuddyBlockInfo {
             block_count /= 2;
         }
 
-        let metadata_len = (levels as usize) * size_of::<BlockBitmap>() + bits;
+        // the free list links of each unit after the bitmap headers, and a byte per unit for the
+        // allocated orders after the bitmaps
+        let metadata_len = (levels as usize) * size_of::<BlockBitmap>() + units * size_of::<FreeBlock>() + bits + units;
         assert!(metadata_len < len);
 
         BuddyBlockInfo {
@@ -124,7 +183,16 @@ impl BuddyBlockInfo {
 
 impl<'a> BuddyBlock<'a> {
     pub fn empty() -> Self {
-        Self { info: BuddyBlockInfo::empty(), used: 0, bitmaps: &mut [] }
+        Self {
+            info: BuddyBlockInfo::empty(),
+            used: 0,
+            bitmaps: &mut [],
+            links: &mut [],
+            orders: &mut [],
+            free_lists: [0; MAX_LEVELS],
+            alloc_fault: None,
+            alloc_calls: 0,
+        }
     }
 
     pub fn info(&self) -> &BuddyBlockInfo {
@@ -144,15 +212,24 @@ impl<'a> BuddyBlock<'a> {
 
         let bitmaps_len = info.levels as usize;
         let bitmaps_bytes = bitmaps_len * size_of::<BlockBitmap>();
+        let links_bytes = info.units * size_of::<FreeBlock>();
 
         let bitmaps = unsafe {
             from_raw_parts_mut(raw_addr as *mut MaybeUninit<BlockBitmap>, bitmaps_len)
         };
+        let links = unsafe {
+            from_raw_parts_mut((raw_addr + bitmaps_bytes) as *mut FreeBlock, info.units)
+        };
         let total_bits = unsafe {
-            from_raw_parts_mut((raw_addr + bitmaps_bytes) as *mut u8, info.metadata_len - bitmaps_bytes)
+            from_raw_parts_mut((raw_addr + bitmaps_bytes + links_bytes) as *mut u8, info.metadata_len - bitmaps_bytes - links_bytes - info.units)
+        };
+        let orders = unsafe {
+            from_raw_parts_mut((raw_addr + info.metadata_len - info.units) as *mut u8, info.units)
         };
 
+        links.fill(FreeBlock { next: 0, prev: 0 });
         total_bits.fill(0);
+        orders.fill(0);
 
         let mut block_count = info.units;
         let mut bits_idx = 0;
@@ -186,43 +263,184 @@ impl<'a> BuddyBlock<'a> {
         assert_eq!(bits_idx, total_bits.len());
         assert_eq!(idx, bitmaps.len());
 
-        Self {
+        let mut buddy = Self {
             info,
             used: 0,
             bitmaps: unsafe { transmute(bitmaps) },
+            links,
+            orders,
+            free_lists: [0; MAX_LEVELS],
+            alloc_fault: None,
+            alloc_calls: 0,
+        };
+
+        // the last block of the levels with an odd number of blocks is free
+        for level in 0..info.levels {
+            let block_count = info.units >> level;
+            if !block_count.is_multiple_of(2) {
+                buddy.link_free(level, block_count - 1);
+            }
         }
+        buddy
     }
 
     pub fn alloc(&mut self, len: usize) -> Option<usize> {
+        self.alloc_with(len, Self::first_free)
+    }
+
+    // block of 2^order units, freed by `dealloc` with UNIT_SIZE << order
+    pub fn alloc_pages(&mut self, order: u32) -> Option<usize> {
+        self.alloc(UNIT_SIZE << order)
+    }
+
+    // `len` bytes rounded to units, whose address is aligned in `align`, a power of two. the
+    // range is reserved out of a free block, so unlike `alloc` it is not a buddy block, and
+    // must be freed by `add_free_range`.
+    pub fn alloc_aligned(&mut self, len: usize, align: usize) -> Option<usize> {
+        self.alloc_aligned_with_bias(len, align, 0)
+    }
+
+    // see `alloc_aligned`, but `addr + bias` is aligned instead of `addr`, e.g. the physical
+    // address of the block. `bias` must be a multiple of UNIT_SIZE.
+    fn alloc_aligned_with_bias(&mut self, len: usize, align: usize, bias: usize) -> Option<usize> {
+        assert_ne!(len, 0);
+        assert!(align.is_power_of_two());
+        assert!(bias.is_multiple_of(UNIT_SIZE));
+
+        // the first free block holding an aligned range. any block of `padded_len` does, and
+        // smaller ones may, so the lists of their levels are searched.
+        let aligned_len = div_ceil(len, UNIT_SIZE) * UNIT_SIZE;
+        let padded_len = aligned_len + align.max(UNIT_SIZE) - UNIT_SIZE;
+        if self.inject_alloc_fault() {
+            return None;
+        }
+        let start = (bitmap_index_for_size(aligned_len)..self.info.levels).find_map(|level| {
+            let mut addr = self.free_lists[level as usize];
+            while addr != 0 {
+                let start = addr.wrapping_add(bias).next_multiple_of(align).wrapping_sub(bias);
+                if start + aligned_len <= addr + (UNIT_SIZE << level) {
+                    return Some(start);
+                }
+                debug_assert!((UNIT_SIZE << level) < padded_len);
+                addr = self.links_of(addr).next;
+            }
+            None
+        })?;
+
+        assert!(self.reserve(start, aligned_len));
+        Some(start)
+    }
+
+    // fault injection for tests of the callers: `fault` is called with the number of each
+    // allocation from now on, counting from 0, and the allocation fails if it returns true.
+    // None turns it off.
+    pub fn set_alloc_fault(&mut self, fault: Option<fn(usize) -> bool>) {
+        self.alloc_fault = fault;
+        self.alloc_calls = 0;
+    }
+
+    fn inject_alloc_fault(&mut self) -> bool {
+        let Some(fault) = self.alloc_fault else {
+            return false;
+        };
+        self.alloc_calls += 1;
+        fault(self.alloc_calls - 1)
+    }
+
+    // walk every level and check that no free block is inside another one or next to its free
+    // buddy, that the counts and the free lists match the bits, and that `used` is the size of
+    // the blocks that are not free
+    pub fn check_invariants(&self) -> Result<(), BuddyCorruption> {
+        let levels = self.info.levels;
+        let mut free_units = 0;
+
+        for level in 0..levels {
+            let block_count = self.info.units >> level;
+            let bits = self.bits(level);
+            let set = bits.iter().map(|x| x.count_ones() as usize).sum::<usize>();
+            let in_range = (0..block_count).filter(|&idx| self.is_free(level, idx)).count();
+            if set != in_range || set != self.bitmaps[level as usize].count {
+                return Err(BuddyCorruption::BadCount(level));
+            }
+            free_units += set << level;
+
+            for idx in (0..block_count).filter(|&idx| self.is_free(level, idx)) {
+                if level + 1 < levels && self.free_ancestor(level + 1, idx / 2).is_some() {
+                    return Err(BuddyCorruption::FreeParent(level, idx));
+                }
+                let has_parent = level + 1 < levels && idx / 2 < self.info.units >> (level + 1);
+                if has_parent && self.is_free(level, idx ^ 1) {
+                    return Err(BuddyCorruption::Unmerged(level, idx));
+                }
+            }
+
+            let mut listed = 0;
+            let mut prev = 0;
+            let mut addr = self.free_lists[level as usize];
+            while addr != 0 {
+                let offset = addr.wrapping_sub(self.info.data_addr());
+                let idx = offset / (UNIT_SIZE << level);
+                let valid = offset.is_multiple_of(UNIT_SIZE << level) && idx < block_count && self.is_free(level, idx);
+                if !valid || listed == set {
+                    return Err(BuddyCorruption::BadFreeList(level));
+                }
+                let block = *self.links_of(addr);
+                if block.prev != prev {
+                    return Err(BuddyCorruption::BadFreeList(level));
+                }
+                listed += 1;
+                prev = addr;
+                addr = block.next;
+            }
+            if listed != set {
+                return Err(BuddyCorruption::BadFreeList(level));
+            }
+        }
+
+        let expected = (self.info.units - free_units) * UNIT_SIZE;
+        if self.used != expected {
+            return Err(BuddyCorruption::BadUsed(self.used, expected));
+        }
+        Ok(())
+    }
+
+    // the allocation path before the free lists, which scans the bitmaps
+    #[cfg(feature = "bench")]
+    #[doc(hidden)]
+    pub fn alloc_by_scan(&mut self, len: usize) -> Option<usize> {
+        self.alloc_with(len, |buddy, level| {
+            let bitmap = buddy.get_bits(level);
+            if bitmap.empty() { None } else { Some(bitmap.first_1()) }
+        })
+    }
+
+    // `find` returns the index of a free block at a level
+    fn alloc_with(&mut self, len: usize, find: impl Fn(&mut Self, u32) -> Option<usize>) -> Option<usize> {
         assert_ne!(len, 0);
 
         let aligned_len = div_ceil(len, UNIT_SIZE) * UNIT_SIZE;
         let bitmap_idx_fit = bitmap_index_for_size(aligned_len);
         let bitmap_len = self.bitmaps.len() as u32;
 
-        if bitmap_idx_fit >= bitmap_len {
+        if bitmap_idx_fit >= bitmap_len || self.inject_alloc_fault() {
             // requested memory is too large
             return None
         }
 
         for bitmap_idx in bitmap_idx_fit..bitmap_len {
-            let mut bitmap = self.get_bits(bitmap_idx);
-
-            if bitmap.empty() {
+            let Some(block_idx) = find(self, bitmap_idx) else {
                 continue;
-            }
-
-            let block_idx = bitmap.first_1();
-            bitmap.set_0(block_idx);
+            };
+            self.unlink_free(bitmap_idx, block_idx);
 
             let mut below_block_idx = block_idx;
             for below in (bitmap_idx_fit..bitmap_idx).rev() {
                 below_block_idx *= 2;
-                let mut below_bitmap = self.get_bits(below);
-                below_bitmap.set_1(below_block_idx + 1);
+                self.link_free(below, below_block_idx + 1);
             }
 
-            self.used += aligned_len;
+            self.used += UNIT_SIZE << bitmap_idx_fit;
+            self.orders[block_idx << bitmap_idx] = bitmap_idx_fit as u8 + 1;
 
             let data_addr = self.info.raw_addr + self.info.data_offset;
             return Some(data_addr + block_idx * (UNIT_SIZE << bitmap_idx));
@@ -232,56 +450,231 @@ impl<'a> BuddyBlock<'a> {
         None
     }
 
-    pub fn dealloc(&mut self, addr: usize, len: usize) {
+    // mark `len` bytes at `addr` as used, e.g. memory of a firmware table or a framebuffer. the
+    // range is rounded to units as in `dealloc`, and may cross block boundaries.
+    // returns false, changing nothing, if a part of the range is not free.
+    pub fn reserve(&mut self, addr: usize, len: usize) -> bool {
+        if len == 0 {
+            return true;
+        }
+
+        let (start, end) = self.unit_range(addr, len);
+        let levels = self.info.levels;
+        if aligned_blocks(start, end, levels).any(|(level, block_idx)| self.free_ancestor(level, block_idx).is_none()) {
+            return false;
+        }
+
+        for (level, block_idx) in aligned_blocks(start, end, levels) {
+            // the free block may be split already for an earlier part of the range
+            let Some(found) = self.free_ancestor(level, block_idx) else {
+                continue;
+            };
+            let found_idx = block_idx >> (found - level);
+            self.unlink_free(found, found_idx);
+            self.link_outside(found, found_idx, start, end);
+        }
+
+        self.used += (end - start) * UNIT_SIZE;
+        true
+    }
+
+    // free `len` bytes at `addr` which were reserved or allocated, in one piece or several. the
+    // range is rounded to units as in `dealloc`, and may cross block boundaries.
+    pub fn add_free_range(&mut self, addr: usize, len: usize) {
         if len == 0 {
             return;
         }
 
+        let (start, end) = self.unit_range(addr, len);
+        let levels = self.info.levels;
+        let free = aligned_blocks(start, end, levels).any(|(level, block_idx)| {
+            self.free_ancestor(level, block_idx).is_some() || self.free_descendant(level, block_idx)
+        });
+        assert!(!free, "range is already free");
+
+        for (level, block_idx) in aligned_blocks(start, end, levels) {
+            self.free_block(level, block_idx);
+        }
+        self.orders[start..end].fill(0);
+    }
+
+    // panics if `try_dealloc` fails
+    pub fn dealloc(&mut self, addr: usize, len: usize) {
+        if let Err(err) = self.try_dealloc(addr, len) {
+            panic!("dealloc of {:#x} bytes at {:#x}: {:?}", len, addr, err);
+        }
+    }
+
+    // free a block allocated by `alloc` with the same `len`. the address and the length are
+    // rounded to units. nothing changes if it fails.
+    pub fn try_dealloc(&mut self, addr: usize, len: usize) -> Result<(), DeallocError> {
+        if len == 0 {
+            return Ok(());
+        }
+
         let data_addr = self.info.data_addr();
         let data_len = self.info.data_len();
 
         let aligned_addr = addr / UNIT_SIZE * UNIT_SIZE;
         let aligned_end = div_ceil(addr + len, UNIT_SIZE) * UNIT_SIZE;
-        let aligned_len = aligned_end - aligned_addr;
+        if aligned_addr < data_addr || aligned_end > data_addr + data_len {
+            return Err(DeallocError::OutOfRange);
+        }
 
-        assert!(data_addr <= aligned_addr && aligned_addr < data_addr + data_len);
-        assert!(data_addr < aligned_end && aligned_end <= data_addr + data_len);
+        let level = bitmap_index_for_size(aligned_end - aligned_addr);
+        let unit = (aligned_addr - data_addr) / UNIT_SIZE;
+        if level >= self.info.levels || !unit.is_multiple_of(1 << level) {
+            return Err(DeallocError::Misaligned);
+        }
+        if self.free_ancestor(level, unit >> level).is_some() {
+            return Err(DeallocError::DoubleFree);
+        }
 
-        let bitmap_idx_fit = bitmap_index_for_size(aligned_len);
-        let bitmap_len = self.bitmaps.len() as u32;
+        match self.orders[unit] {
+            0 => return Err(DeallocError::Misaligned),
+            order if order as u32 != level + 1 => return Err(DeallocError::SizeMismatch(UNIT_SIZE << (order - 1))),
+            _ => {}
+        }
 
-        assert!(bitmap_idx_fit < bitmap_len);
+        self.orders[unit] = 0;
+        self.free_block(level, unit >> level);
+        Ok(())
+    }
 
-        let mut block_idx = (aligned_addr - data_addr) / (UNIT_SIZE << bitmap_idx_fit);
-        let mut current = bitmap_idx_fit;
+    // give a used block back, merging it with its free buddies
+    fn free_block(&mut self, level: u32, block_idx: usize) {
+        let bitmap_len = self.bitmaps.len() as u32;
+
+        let mut block_idx = block_idx;
+        let mut current = level;
         loop {
-            let mut current_bitmap = self.get_bits(current);
+            let current_bitmap = self.get_bits(current);
             assert!(!current_bitmap.get(block_idx));
-            current_bitmap.set_1(block_idx);
 
             let buddy_idx = block_idx ^ 1;
-            if current_bitmap.get(buddy_idx) {
-                if current + 1 >= bitmap_len {
-                    break;
-                }
-
-                current_bitmap.set_0(buddy_idx);
-                current_bitmap.set_0(block_idx);
+            if current_bitmap.get(buddy_idx) && current + 1 < bitmap_len {
+                self.unlink_free(current, buddy_idx);
 
                 block_idx /= 2;
                 current += 1;
             }
             else {
+                self.link_free(current, block_idx);
                 break;
             }
         }
 
-        self.used -= aligned_len;
+        self.used -= UNIT_SIZE << level;
     }
 
     fn get_bits(&mut self, bitmap_idx: u32) -> BlockBitmapRef {
         BlockBitmapRef::from(&mut self.bitmaps, self.info.units, bitmap_idx)
     }
+
+    fn block_addr(&self, level: u32, block_idx: usize) -> usize {
+        self.info.data_addr() + block_idx * (UNIT_SIZE << level)
+    }
+
+    // units [start, end) covering `len` bytes at `addr`, which must be in the data
+    fn unit_range(&self, addr: usize, len: usize) -> (usize, usize) {
+        let data_addr = self.info.data_addr();
+        let aligned_addr = addr / UNIT_SIZE * UNIT_SIZE;
+        let aligned_end = div_ceil(addr + len, UNIT_SIZE) * UNIT_SIZE;
+
+        assert!(data_addr <= aligned_addr && aligned_end <= data_addr + self.info.data_len(), "range is out of the data");
+        ((aligned_addr - data_addr) / UNIT_SIZE, (aligned_end - data_addr) / UNIT_SIZE)
+    }
+
+    // level of the free block containing a block, which is the block itself or one of its
+    // ancestors, or None if a part of the block is used
+    fn free_ancestor(&self, level: u32, block_idx: usize) -> Option<u32> {
+        for current in level..self.info.levels {
+            let idx = block_idx >> (current - level);
+            if idx >= self.info.units >> current {
+                break;
+            }
+            if self.is_free(current, idx) {
+                return Some(current);
+            }
+        }
+        None
+    }
+
+    // whether a block below the block is free
+    fn free_descendant(&self, level: u32, block_idx: usize) -> bool {
+        (0..level).any(|below| {
+            let shift = level - below;
+            ((block_idx << shift)..((block_idx + 1) << shift)).any(|idx| self.is_free(below, idx))
+        })
+    }
+
+    // free the parts of a block outside units [start, end), splitting it as needed. the parts
+    // inside are left used.
+    fn link_outside(&mut self, level: u32, block_idx: usize, start: usize, end: usize) {
+        let (first, last) = (block_idx << level, (block_idx + 1) << level);
+        if last <= start || end <= first {
+            self.link_free(level, block_idx);
+        } else if first < start || end < last {
+            self.link_outside(level - 1, block_idx * 2, start, end);
+            self.link_outside(level - 1, block_idx * 2 + 1, start, end);
+        }
+    }
+
+    fn bits(&self, level: u32) -> &[u8] {
+        let block_count = self.info.units >> level;
+        unsafe { core::slice::from_raw_parts(self.bitmaps[level as usize].bits, (block_count - 1) / 8 + 1) }
+    }
+
+    fn is_free(&self, level: u32, block_idx: usize) -> bool {
+        (self.bits(level)[block_idx / 8] & (1 << (block_idx % 8))) != 0
+    }
+
+    fn first_free(&mut self, level: u32) -> Option<usize> {
+        let addr = self.free_lists[level as usize];
+        if addr == 0 {
+            return None;
+        }
+        Some((addr - self.info.data_addr()) / (UNIT_SIZE << level))
+    }
+
+    // links of the free block at `addr`, which must be in the data
+    fn links_of(&self, addr: usize) -> &FreeBlock {
+        &self.links[(addr - self.info.data_addr()) / UNIT_SIZE]
+    }
+
+    fn links_of_mut(&mut self, addr: usize) -> &mut FreeBlock {
+        &mut self.links[(addr - self.info.data_addr()) / UNIT_SIZE]
+    }
+
+    // set the bit of a block and push it to the free list of its level
+    fn link_free(&mut self, level: u32, block_idx: usize) {
+        self.get_bits(level).set_1(block_idx);
+
+        let addr = self.block_addr(level, block_idx);
+        let head = self.free_lists[level as usize];
+        *self.links_of_mut(addr) = FreeBlock { next: head, prev: 0 };
+        if head != 0 {
+            self.links_of_mut(head).prev = addr;
+        }
+        self.free_lists[level as usize] = addr;
+    }
+
+    // clear the bit of a free block and remove it from the free list of its level. the bit of
+    // the block must be set.
+    fn unlink_free(&mut self, level: u32, block_idx: usize) {
+        self.get_bits(level).set_0(block_idx);
+
+        let addr = self.block_addr(level, block_idx);
+        let block = *self.links_of(addr);
+        if block.prev == 0 {
+            self.free_lists[level as usize] = block.next;
+        } else {
+            self.links_of_mut(block.prev).next = block.next;
+        }
+        if block.next != 0 {
+            self.links_of_mut(block.next).prev = block.prev;
+        }
+    }
 }
 
 impl<'a> BlockBitmapRef<'a> {
@@ -316,10 +709,12 @@ impl<'a> BlockBitmapRef<'a> {
         }
     }
 
+    #[cfg(feature = "bench")]
     fn empty(&self) -> bool {
         *self.count == 0
     }
 
+    #[cfg(feature = "bench")]
     fn first_1(&self) -> usize {
         assert!(!self.empty());
 
@@ -337,6 +732,24 @@ impl<'a> BlockBitmapRef<'a> {
     }
 }
 
+// split units [start, end) into the largest aligned blocks, as (level, block index)
+fn aligned_blocks(mut start: usize, end: usize, levels: u32) -> impl Iterator<Item = (u32, usize)> {
+    core::iter::from_fn(move || {
+        if start >= end {
+            return None;
+        }
+
+        let mut level = 0;
+        while level + 1 < levels && start.is_multiple_of(2 << level) && start + (2 << level) <= end {
+            level += 1;
+        }
+
+        let block = (level, start >> level);
+        start += 1 << level;
+        Some(block)
+    })
+}
+
 fn bitmap_index_for_size(size: usize) -> u32 {
     let mut idx = 0;
     while (UNIT_SIZE << idx) < size {
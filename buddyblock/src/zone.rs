@@ -0,0 +1,206 @@
+use crate::{BuddyBlock, BuddyBlockInfo, DeallocError, UNIT_SIZE};
+
+pub const MAX_RANGES: usize = 32;
+
+// smaller ranges cannot hold their metadata and a block
+const MIN_RANGE_LEN: usize = 4 * UNIT_SIZE;
+
+// physical memory below 16 MiB for ISA DMA, below 4 GiB for 32-bit devices, or anywhere
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    Dma,
+    Dma32,
+    Normal,
+}
+
+pub const ZONES: [Zone; 3] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+// contiguous range of one zone, managed by its own BuddyBlock
+pub struct ZoneRange<'a> {
+    zone: Zone,
+    phys_addr: usize,
+    buddy: BuddyBlock<'a>,
+}
+
+// buddy allocator of several discontiguous ranges. ranges are split at the zone limits, so that
+// each belongs to a single zone.
+pub struct BuddyZones<'a> {
+    ranges: [Option<ZoneRange<'a>>; MAX_RANGES],
+    count: usize,
+}
+
+impl Zone {
+    // physical addresses of the zone are below this
+    pub const fn limit(self) -> usize {
+        match self {
+            Zone::Dma => 0x0100_0000,
+            Zone::Dma32 => 0x1_0000_0000,
+            Zone::Normal => usize::MAX,
+        }
+    }
+
+    // zone of a physical address
+    pub fn of(phys_addr: usize) -> Self {
+        ZONES.into_iter().find(|x| phys_addr < x.limit()).unwrap_or(Zone::Normal)
+    }
+}
+
+impl<'a> ZoneRange<'a> {
+    pub fn zone(&self) -> Zone {
+        self.zone
+    }
+
+    // physical address of the first byte of the range, which is at `info().raw_addr()`
+    pub fn phys_addr(&self) -> usize {
+        self.phys_addr
+    }
+
+    pub fn info(&self) -> &BuddyBlockInfo {
+        self.buddy.info()
+    }
+
+    pub fn used(&self) -> usize {
+        self.buddy.used()
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        let info = self.buddy.info();
+        (info.data_addr()..info.data_addr() + info.data_len()).contains(&addr)
+    }
+}
+
+impl<'a> BuddyZones<'a> {
+    pub const fn new() -> Self {
+        Self {
+            ranges: [const { None }; MAX_RANGES],
+            count: 0,
+        }
+    }
+
+    // manage `len` bytes at `addr`, whose physical address is `phys_addr`, except the physical
+    // ranges in `reserved` given as (address, length). the range is split around the reserved
+    // ranges and at the zone limits, dropping pieces too small to manage.
+    // returns false if some pieces were dropped because there are already MAX_RANGES ranges.
+    /// # Safety
+    /// the memory must be accessible at `addr` and unused until given back by the allocator
+    pub unsafe fn add_range(&mut self, addr: usize, phys_addr: usize, len: usize, reserved: &[(usize, usize)]) -> bool {
+        let end = phys_addr + len;
+        let mut cursor = phys_addr;
+
+        while cursor < end {
+            if let Some(&(start, len)) = reserved.iter().find(|&&(start, len)| (start..start + len).contains(&cursor)) {
+                cursor = start + len;
+                continue;
+            }
+
+            let mut piece_end = end;
+            let limits = ZONES.iter().map(|x| x.limit());
+            let reserved_starts = reserved.iter().map(|x| x.0);
+            for boundary in limits.chain(reserved_starts) {
+                if cursor < boundary && boundary < piece_end {
+                    piece_end = boundary;
+                }
+            }
+
+            let start = cursor.next_multiple_of(UNIT_SIZE);
+            let stop = piece_end / UNIT_SIZE * UNIT_SIZE;
+            if start < stop && stop - start >= MIN_RANGE_LEN {
+                if self.count == MAX_RANGES {
+                    return false;
+                }
+
+                let buddy = unsafe { BuddyBlock::new(addr + (start - phys_addr), stop - start) };
+                self.ranges[self.count] = Some(ZoneRange { zone: Zone::of(start), phys_addr: start, buddy });
+                self.count += 1;
+            }
+            cursor = piece_end;
+        }
+        true
+    }
+
+    pub fn ranges(&self) -> impl Iterator<Item = &ZoneRange<'a>> {
+        self.ranges[..self.count].iter().flatten()
+    }
+
+    // from the ranges of `zone`, or of the lower zones if they are full
+    pub fn alloc(&mut self, len: usize, zone: Zone) -> Option<usize> {
+        self.alloc_with(zone, |range| range.buddy.alloc(len))
+    }
+
+    // see `BuddyBlock::alloc_pages`
+    pub fn alloc_pages(&mut self, order: u32, zone: Zone) -> Option<usize> {
+        self.alloc(UNIT_SIZE << order, zone)
+    }
+
+    // see `BuddyBlock::alloc_aligned`, but the physical address is aligned in `align`. the range
+    // must be freed by `add_free_range`.
+    pub fn alloc_aligned(&mut self, len: usize, align: usize, zone: Zone) -> Option<usize> {
+        self.alloc_with(zone, |range| {
+            let bias = range.phys_addr.wrapping_sub(range.buddy.info().raw_addr());
+            range.buddy.alloc_aligned_with_bias(len, align, bias)
+        })
+    }
+
+    // see `BuddyBlock::alloc_aligned`: the address is aligned in `align`, whatever the physical
+    // address is. the range must be freed by `add_free_range`.
+    pub fn alloc_aligned_virt(&mut self, len: usize, align: usize, zone: Zone) -> Option<usize> {
+        self.alloc_with(zone, |range| range.buddy.alloc_aligned(len, align))
+    }
+
+    // try `f` on the ranges of `zone`, then of the lower zones
+    fn alloc_with(&mut self, zone: Zone, mut f: impl FnMut(&mut ZoneRange<'a>) -> Option<usize>) -> Option<usize> {
+        for current in ZONES.into_iter().rev().filter(|&x| x <= zone) {
+            for range in self.ranges[..self.count].iter_mut().flatten().filter(|x| x.zone == current) {
+                if let Some(addr) = f(range) {
+                    return Some(addr);
+                }
+            }
+        }
+        None
+    }
+
+    pub fn dealloc(&mut self, addr: usize, len: usize) {
+        self.range_of(addr).buddy.dealloc(addr, len);
+    }
+
+    // see `BuddyBlock::try_dealloc`
+    pub fn try_dealloc(&mut self, addr: usize, len: usize) -> Result<(), DeallocError> {
+        let range = self.ranges[..self.count].iter_mut().flatten()
+            .find(|x| x.contains(addr))
+            .ok_or(DeallocError::OutOfRange)?;
+        range.buddy.try_dealloc(addr, len)
+    }
+
+    // see `BuddyBlock::add_free_range`. the range must be in a single range.
+    pub fn add_free_range(&mut self, addr: usize, len: usize) {
+        self.range_of(addr).buddy.add_free_range(addr, len);
+    }
+
+    fn range_of(&mut self, addr: usize) -> &mut ZoneRange<'a> {
+        self.ranges[..self.count].iter_mut().flatten()
+            .find(|x| x.contains(addr))
+            .expect("address is not in any range")
+    }
+
+    // physical address of `addr` allocated by `alloc`
+    pub fn phys_addr(&self, addr: usize) -> Option<usize> {
+        self.ranges()
+            .find(|x| x.contains(addr))
+            .map(|x| x.phys_addr + (addr - x.info().raw_addr()))
+    }
+
+    // bytes for allocations, of every range or of the ranges of `zone`
+    pub fn data_len(&self, zone: Option<Zone>) -> usize {
+        self.ranges().filter(|x| zone.is_none_or(|z| x.zone == z)).map(|x| x.info().data_len()).sum()
+    }
+
+    pub fn used(&self, zone: Option<Zone>) -> usize {
+        self.ranges().filter(|x| zone.is_none_or(|z| x.zone == z)).map(|x| x.used()).sum()
+    }
+}
+
+impl<'a> Default for BuddyZones<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,6 +1,6 @@
 use core::mem::size_of;
 use core::slice::from_raw_parts_mut;
-use buddyblock::{BuddyBlock, UNIT_SIZE};
+use buddyblock::{BuddyBlock, BuddyCorruption, BuddyZones, DeallocError, UNIT_SIZE, Zone};
 
 #[repr(C, align(4096))]
 #[derive(Clone, Copy)]
@@ -37,18 +37,19 @@ fn test_new() {
         (level, bitlen)
     }
 
-    let units = 0x1ff000 / UNIT_SIZE;
+    // the metadata, with 16 bytes of free list links per unit, takes the first 3 units
+    let units = 0x1fd000 / UNIT_SIZE;
     let (level, bitlen) = calc_lev_bitlen(units);
-    let metalen = (level as usize) * 16 + bitlen;
+    let metalen = (level as usize) * 16 + units * 16 + bitlen + units;
 
     println!("{:?}", buddy.info());
     assert_eq!(buddy.info().raw_addr(), begin);
     assert_eq!(buddy.info().total_len(), len);
     assert_eq!(buddy.info().metadata_len(), metalen);
-    assert_eq!(buddy.info().data_offset(), UNIT_SIZE);
+    assert_eq!(buddy.info().data_offset(), 3 * UNIT_SIZE);
     assert_eq!(buddy.info().units(), units);
     assert_eq!(buddy.info().levels(), level);
-    assert_eq!(buddy.info().data_addr(), begin + UNIT_SIZE);
+    assert_eq!(buddy.info().data_addr(), begin + 3 * UNIT_SIZE);
 }
 
 #[test]
@@ -101,3 +102,547 @@ fn test_seq() {
         println!();
     }
 }
+
+#[test]
+fn test_random_alloc_dealloc() {
+    let TestBuddy(mut buddy, _mem) = create_buddy();
+
+    // xorshift, to avoid a dependency
+    let mut seed: u64 = 0x2545f4914f6cdd1d;
+    let mut next = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+
+    let mut live: Vec<(usize, usize)> = Vec::new();
+    for round in 0..20000 {
+        if live.is_empty() || next() % 3 != 0 {
+            let len = UNIT_SIZE << (next() % 5);
+            let Some(addr) = buddy.alloc(len) else {
+                continue;
+            };
+            assert_eq!((addr - buddy.info().data_addr()) % len, 0, "block is not aligned in its size");
+            assert!(live.iter().all(|&(x, xlen)| addr + len <= x || x + xlen <= addr), "blocks overlap");
+            unsafe { *(addr as *mut u64) = round; }
+            live.push((addr, len));
+        }
+        else {
+            let (addr, len) = live.swap_remove(next() as usize % live.len());
+            buddy.dealloc(addr, len);
+        }
+        assert_eq!(buddy.used(), live.iter().map(|x| x.1).sum::<usize>());
+    }
+
+    for (addr, len) in live {
+        buddy.dealloc(addr, len);
+    }
+    assert_eq!(buddy.used(), 0);
+
+    // everything merged back, so the largest block can be allocated again
+    let top = UNIT_SIZE << (buddy.info().levels() - 1);
+    assert!(buddy.alloc(top).is_some());
+}
+
+// every unit allocation must avoid [start, end)
+fn alloc_all_units(buddy: &mut BuddyBlock, start: usize, end: usize) -> Vec<usize> {
+    let mut units = Vec::new();
+    while let Some(addr) = buddy.alloc(UNIT_SIZE) {
+        assert!(addr < start || end <= addr, "reserved memory is allocated");
+        units.push(addr);
+    }
+    units
+}
+
+#[test]
+fn test_reserve_across_blocks() {
+    let TestBuddy(mut buddy, _mem) = create_buddy();
+    let data = buddy.info().data_addr();
+
+    // units [3, 13) cross the boundaries of blocks of every size up to 16 units
+    let (start, end) = (data + 3 * UNIT_SIZE, data + 13 * UNIT_SIZE);
+    assert!(buddy.reserve(start + 0x10, end - start - 0x20)); // rounded out to units
+    assert_eq!(buddy.used(), 10 * UNIT_SIZE);
+
+    // overlapping reservations fail without changing anything
+    assert!(!buddy.reserve(data + 12 * UNIT_SIZE, 2 * UNIT_SIZE));
+    assert!(!buddy.reserve(data, 4 * UNIT_SIZE));
+    assert_eq!(buddy.used(), 10 * UNIT_SIZE);
+
+    let units = alloc_all_units(&mut buddy, start, end);
+    assert_eq!(units.len(), buddy.info().units() - 10);
+    for addr in units {
+        buddy.dealloc(addr, UNIT_SIZE);
+    }
+
+    buddy.add_free_range(start, end - start);
+    assert_eq!(buddy.used(), 0);
+    let top = UNIT_SIZE << (buddy.info().levels() - 1);
+    assert!(buddy.alloc(top).is_some());
+}
+
+#[test]
+fn test_reserve_keeps_content() {
+    let TestBuddy(mut buddy, _mem) = create_buddy();
+    let data = buddy.info().data_addr();
+
+    // e.g. a firmware table, in the middle of a free block
+    let table = unsafe { from_raw_parts_mut((data + UNIT_SIZE) as *mut u64, 2 * UNIT_SIZE / 8) };
+    for (idx, x) in table.iter_mut().enumerate() {
+        *x = 0x5a5a_0000_0000_0000 | idx as u64;
+    }
+
+    assert!(buddy.reserve(data + UNIT_SIZE, 2 * UNIT_SIZE));
+    assert_eq!(buddy.check_invariants(), Ok(()));
+    assert!(table.iter().enumerate().all(|(idx, &x)| x == 0x5a5a_0000_0000_0000 | idx as u64));
+}
+
+#[test]
+fn test_reserve_allocated_memory() {
+    let TestBuddy(mut buddy, _mem) = create_buddy();
+    let data = buddy.info().data_addr();
+
+    let addr = buddy.alloc(4 * UNIT_SIZE).unwrap();
+    assert!(!buddy.reserve(addr + UNIT_SIZE, UNIT_SIZE));
+    assert!(!buddy.reserve(data, buddy.info().data_len()));
+    assert_eq!(buddy.used(), 4 * UNIT_SIZE);
+
+    // once freed, a part of the block can be reserved and the rest is allocated around it
+    buddy.dealloc(addr, 4 * UNIT_SIZE);
+    assert!(buddy.reserve(addr + UNIT_SIZE, UNIT_SIZE));
+    assert_eq!(buddy.used(), UNIT_SIZE);
+    assert!(buddy.alloc(4 * UNIT_SIZE).is_some_and(|x| x != addr));
+    let units = alloc_all_units(&mut buddy, addr + UNIT_SIZE, addr + 2 * UNIT_SIZE);
+    assert!(units.contains(&addr) && units.contains(&(addr + 2 * UNIT_SIZE)));
+}
+
+#[test]
+fn test_add_free_range_in_pieces() {
+    let TestBuddy(mut buddy, _mem) = create_buddy();
+    let data = buddy.info().data_addr();
+    let len = buddy.info().data_len();
+
+    // the whole data, including the odd blocks at the end of the levels
+    assert!(buddy.reserve(data, len));
+    assert_eq!(buddy.used(), len);
+    assert!(buddy.alloc(UNIT_SIZE).is_none());
+
+    // handed back in pieces not aligned in buddy blocks
+    let cuts = [0, 1, 7, 8, 37, 100, len / UNIT_SIZE];
+    for pair in cuts.windows(2).rev() {
+        buddy.add_free_range(data + pair[0] * UNIT_SIZE, (pair[1] - pair[0]) * UNIT_SIZE);
+    }
+    assert_eq!(buddy.used(), 0);
+
+    // allocated blocks can be freed together as a range
+    let units = alloc_all_units(&mut buddy, 0, 0);
+    assert_eq!(units.len(), len / UNIT_SIZE);
+    buddy.add_free_range(data, len);
+    assert_eq!(buddy.used(), 0);
+    let top = UNIT_SIZE << (buddy.info().levels() - 1);
+    assert!(buddy.alloc(top).is_some());
+}
+
+#[test]
+#[should_panic(expected = "range is already free")]
+fn test_add_free_range_twice() {
+    let TestBuddy(mut buddy, _mem) = create_buddy();
+    let data = buddy.info().data_addr();
+
+    assert!(buddy.reserve(data + UNIT_SIZE, 3 * UNIT_SIZE));
+    buddy.add_free_range(data + UNIT_SIZE, 3 * UNIT_SIZE);
+    buddy.add_free_range(data + 2 * UNIT_SIZE, UNIT_SIZE);
+}
+
+#[test]
+#[should_panic(expected = "range is already free")]
+fn test_add_free_range_over_free_part() {
+    let TestBuddy(mut buddy, _mem) = create_buddy();
+
+    // the second range contains the unit freed by the first one
+    let addr = buddy.alloc(4 * UNIT_SIZE).unwrap();
+    buddy.add_free_range(addr, UNIT_SIZE);
+    assert_eq!(buddy.check_invariants(), Ok(()));
+    buddy.add_free_range(addr, 2 * UNIT_SIZE);
+}
+
+#[test]
+fn test_alloc_pages() {
+    let TestBuddy(mut buddy, _mem) = create_buddy();
+    let data = buddy.info().data_addr();
+
+    let addr = buddy.alloc_pages(3).unwrap();
+    assert_eq!((addr - data) % (UNIT_SIZE << 3), 0);
+    assert_eq!(buddy.used(), UNIT_SIZE << 3);
+    assert!(buddy.alloc_pages(buddy.info().levels()).is_none());
+
+    buddy.dealloc(addr, UNIT_SIZE << 3);
+    assert_eq!(buddy.used(), 0);
+}
+
+#[test]
+fn test_alloc_aligned() {
+    let TestBuddy(mut buddy, _mem) = create_buddy();
+
+    let mut live: Vec<(usize, usize)> = Vec::new();
+    for (idx, align) in [0x1000, 0x2000, 0x10000, 0x40000, 0x1000, 0x8000].into_iter().cycle().take(24).enumerate() {
+        let len = (idx % 5 + 1) * UNIT_SIZE - 0x10;
+        let Some(addr) = buddy.alloc_aligned(len, align) else {
+            continue;
+        };
+        assert_eq!(addr % align, 0);
+        assert!(live.iter().all(|&(x, xlen)| addr + len <= x || x + xlen <= addr), "ranges overlap");
+        live.push((addr, len));
+
+        // only the requested units are used, the padding is free
+        let used: usize = live.iter().map(|x| x.1.next_multiple_of(UNIT_SIZE)).sum();
+        assert_eq!(buddy.used(), used);
+    }
+    assert!(live.len() > 12);
+
+    for (addr, len) in live {
+        buddy.add_free_range(addr, len);
+    }
+    assert_eq!(buddy.used(), 0);
+    let top = UNIT_SIZE << (buddy.info().levels() - 1);
+    assert!(buddy.alloc(top).is_some());
+}
+
+#[test]
+fn test_check_invariants_finds_corruption() {
+    let TestBuddy(mut buddy, mem) = create_buddy();
+    assert_eq!(buddy.check_invariants(), Ok(()));
+
+    let addr = buddy.alloc(UNIT_SIZE).unwrap();
+    assert_eq!(buddy.check_invariants(), Ok(()));
+
+    // the bits of level 0 follow the bitmap headers and the free list links of the units
+    let unit = (addr - buddy.info().data_addr()) / UNIT_SIZE;
+    let links = unsafe { (mem.as_ptr() as *mut u8).add(buddy.info().levels() as usize * 16) };
+    let bits = unsafe { links.add(buddy.info().units() * 16) };
+    unsafe { *bits.add(unit / 8) ^= 1 << (unit % 8); }
+    assert_eq!(buddy.check_invariants(), Err(BuddyCorruption::BadCount(0)));
+    unsafe { *bits.add(unit / 8) ^= 1 << (unit % 8); }
+
+    // a free block is linked through the links of its first unit
+    let free = buddy.alloc(UNIT_SIZE).unwrap();
+    buddy.dealloc(free, UNIT_SIZE);
+    assert_eq!(buddy.check_invariants(), Ok(()));
+    let unit = (free - buddy.info().data_addr()) / UNIT_SIZE;
+    unsafe { *(links.add(unit * 16) as *mut usize) = free + 0x10; }
+    assert!(matches!(buddy.check_invariants(), Err(BuddyCorruption::BadFreeList(_))));
+}
+
+#[test]
+fn test_try_dealloc_errors() {
+    let TestBuddy(mut buddy, _mem) = create_buddy();
+    let data = buddy.info().data_addr();
+
+    assert!(buddy.reserve(data, UNIT_SIZE));
+    let addr = buddy.alloc(4 * UNIT_SIZE).unwrap();
+    let small = buddy.alloc(UNIT_SIZE).unwrap();
+    let used = buddy.used();
+
+    assert_eq!(buddy.try_dealloc(data - UNIT_SIZE, UNIT_SIZE), Err(DeallocError::OutOfRange));
+    assert_eq!(buddy.try_dealloc(data + buddy.info().data_len(), UNIT_SIZE), Err(DeallocError::OutOfRange));
+    assert_eq!(buddy.try_dealloc(data, UNIT_SIZE), Err(DeallocError::Misaligned)); // reserved
+    assert_eq!(buddy.try_dealloc(addr + UNIT_SIZE, UNIT_SIZE), Err(DeallocError::Misaligned));
+    assert_eq!(buddy.try_dealloc(addr + 2 * UNIT_SIZE, 2 * UNIT_SIZE), Err(DeallocError::Misaligned));
+    assert_eq!(buddy.try_dealloc(addr, 2 * UNIT_SIZE), Err(DeallocError::SizeMismatch(4 * UNIT_SIZE)));
+
+    // a wrong length would free the buddy of the block too
+    assert!(matches!(buddy.try_dealloc(small, 2 * UNIT_SIZE), Err(DeallocError::Misaligned | DeallocError::SizeMismatch(UNIT_SIZE))));
+    assert_eq!(buddy.used(), used);
+    assert_eq!(buddy.check_invariants(), Ok(()));
+
+    assert_eq!(buddy.try_dealloc(addr, 3 * UNIT_SIZE), Ok(()));
+    assert_eq!(buddy.try_dealloc(addr, 4 * UNIT_SIZE), Err(DeallocError::DoubleFree));
+    assert_eq!(buddy.try_dealloc(addr + UNIT_SIZE, UNIT_SIZE), Err(DeallocError::DoubleFree));
+    assert_eq!(buddy.try_dealloc(small, UNIT_SIZE), Ok(()));
+    assert_eq!(buddy.try_dealloc(small, UNIT_SIZE), Err(DeallocError::DoubleFree));
+    assert_eq!(buddy.used(), UNIT_SIZE);
+    assert_eq!(buddy.check_invariants(), Ok(()));
+}
+
+#[test]
+#[should_panic(expected = "DoubleFree")]
+fn test_dealloc_twice() {
+    let TestBuddy(mut buddy, _mem) = create_buddy();
+
+    let addr = buddy.alloc(UNIT_SIZE).unwrap();
+    buddy.dealloc(addr, UNIT_SIZE);
+    buddy.dealloc(addr, UNIT_SIZE);
+}
+
+// reference allocator for `run_model`: whether each unit is used
+struct Model {
+    units: Vec<bool>,
+}
+
+impl Model {
+    fn is_free(&self, start: usize, count: usize) -> bool {
+        self.units[start..start + count].iter().all(|x| !x)
+    }
+
+    fn mark(&mut self, start: usize, count: usize, used: bool) {
+        for unit in &mut self.units[start..start + count] {
+            assert_ne!(*unit, used, "unit is marked twice");
+            *unit = used;
+        }
+    }
+
+    // whether there is a free block of 2^level units aligned in its size
+    fn has_free_block(&self, level: u32) -> bool {
+        let count = 1 << level;
+        (0..self.units.len() / count).any(|idx| self.is_free(idx * count, count))
+    }
+
+    fn used(&self) -> usize {
+        self.units.iter().filter(|x| **x).count() * UNIT_SIZE
+    }
+}
+
+// random allocations, frees and reservations checked against `Model` and `check_invariants`.
+// allocations fail where `fault` returns true, and then nothing may change.
+fn run_model(mut seed: u64, fault: Option<fn(usize) -> bool>) {
+    let TestBuddy(mut buddy, _mem) = create_buddy();
+    let data = buddy.info().data_addr();
+    let mut model = Model { units: vec![false; buddy.info().units()] };
+    buddy.set_alloc_fault(fault);
+
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed as usize
+    };
+
+    // (address, length, freed by `add_free_range` rather than `dealloc`)
+    let mut live: Vec<(usize, usize, bool)> = Vec::new();
+    let mut alloc_calls = 0;
+    let mut injected = 0;
+    for _ in 0..5000 {
+        let used = buddy.used();
+        let op = next() % 8;
+        match op {
+            0..=2 => {
+                let len = (next() % 16 + 1) * UNIT_SIZE - next() % UNIT_SIZE;
+                let faulted = fault.is_some_and(|f| f(alloc_calls));
+                alloc_calls += 1;
+
+                let level = len.div_ceil(UNIT_SIZE).next_power_of_two().trailing_zeros();
+                let block_len = UNIT_SIZE << level;
+                match buddy.alloc(len) {
+                    Some(addr) => {
+                        assert!(!faulted, "injected fault is ignored");
+                        assert_eq!((addr - data) % block_len, 0);
+                        model.mark((addr - data) / UNIT_SIZE, 1 << level, true);
+                        live.push((addr, len, false));
+                    }
+                    None if faulted => {
+                        injected += 1;
+                        assert_eq!(buddy.used(), used);
+                    }
+                    None => assert!(!model.has_free_block(level), "alloc fails with a free block"),
+                }
+            }
+            3 => {
+                let len = (next() % 4 + 1) * UNIT_SIZE;
+                let align = UNIT_SIZE << (next() % 5);
+                let faulted = fault.is_some_and(|f| f(alloc_calls));
+                alloc_calls += 1;
+
+                match buddy.alloc_aligned(len, align) {
+                    Some(addr) => {
+                        assert!(!faulted, "injected fault is ignored");
+                        assert_eq!(addr % align, 0);
+                        model.mark((addr - data) / UNIT_SIZE, len / UNIT_SIZE, true);
+                        live.push((addr, len, true));
+                    }
+                    None if faulted => injected += 1,
+                    None => {}
+                }
+            }
+            4 => {
+                let count = next() % 8 + 1;
+                let start = next() % (model.units.len() - count);
+                let free = model.is_free(start, count);
+                assert_eq!(buddy.reserve(data + start * UNIT_SIZE, count * UNIT_SIZE), free);
+                if free {
+                    model.mark(start, count, true);
+                    live.push((data + start * UNIT_SIZE, count * UNIT_SIZE, true));
+                }
+            }
+            _ if !live.is_empty() => {
+                let (addr, len, range) = live.swap_remove(next() % live.len());
+                let start = (addr - data) / UNIT_SIZE;
+                if range {
+                    buddy.add_free_range(addr, len);
+                    model.mark(start, len / UNIT_SIZE, false);
+                }
+                else {
+                    buddy.dealloc(addr, len);
+                    model.mark(start, len.div_ceil(UNIT_SIZE).next_power_of_two(), false);
+                }
+            }
+            _ => {}
+        }
+
+        assert_eq!(buddy.check_invariants(), Ok(()), "after op {}", op);
+        assert_eq!(buddy.used(), model.used());
+    }
+    assert_eq!(injected > 0, fault.is_some());
+
+    buddy.set_alloc_fault(None);
+    for (addr, len, range) in live {
+        if range {
+            buddy.add_free_range(addr, len);
+        }
+        else {
+            buddy.dealloc(addr, len);
+        }
+    }
+    assert_eq!(buddy.check_invariants(), Ok(()));
+    assert_eq!(buddy.used(), 0);
+}
+
+#[test]
+fn test_model() {
+    run_model(0x2545f4914f6cdd1d, None);
+    run_model(0x9e3779b97f4a7c15, None);
+}
+
+#[test]
+fn test_model_with_alloc_faults() {
+    run_model(0x2545f4914f6cdd1d, Some(|n| n % 7 == 3));
+    run_model(0x9e3779b97f4a7c15, Some(|n| n > 500 && n < 520));
+}
+
+fn page_vec(len: usize) -> Vec<Page> {
+    vec![Page { buf: [0; 512] }; len / UNIT_SIZE]
+}
+
+#[test]
+fn test_zones_split_at_limits_and_reserved() {
+    let mem = page_vec(0x200000);
+    let mut zones = BuddyZones::new();
+    let phys = 0x00f00000;
+    assert!(unsafe { zones.add_range(mem.as_ptr() as usize, phys, 0x200000, &[(0x01080000, 0x10000)]) });
+
+    let ranges: Vec<_> = zones.ranges().map(|x| (x.zone(), x.phys_addr(), x.info().total_len())).collect();
+    assert_eq!(ranges, [
+        (Zone::Dma, 0x00f00000, 0x100000),
+        (Zone::Dma32, 0x01000000, 0x80000),
+        (Zone::Dma32, 0x01090000, 0x70000),
+    ]);
+    for range in zones.ranges() {
+        assert_eq!(range.info().raw_addr() - zones.ranges().next().unwrap().info().raw_addr(), range.phys_addr() - phys);
+    }
+    assert_eq!(zones.used(None), 0);
+    assert_eq!(zones.data_len(None), zones.ranges().map(|x| x.info().data_len()).sum::<usize>());
+}
+
+#[test]
+fn test_zones_alloc_from_zone() {
+    let mem = page_vec(0x200000);
+    let mut zones = BuddyZones::new();
+    let reserved = (0x01080000, 0x10000);
+    unsafe { zones.add_range(mem.as_ptr() as usize, 0x00f00000, 0x200000, &[reserved]); }
+
+    // a request for a zone is served by it or a lower zone, never by a higher one
+    let mut dma = Vec::new();
+    while let Some(addr) = zones.alloc(UNIT_SIZE, Zone::Dma) {
+        assert!(zones.phys_addr(addr).unwrap() < Zone::Dma.limit());
+        dma.push(addr);
+    }
+    assert_eq!(zones.used(Some(Zone::Dma)), zones.data_len(Some(Zone::Dma)));
+    assert_eq!(zones.used(Some(Zone::Dma32)), 0);
+
+    let mut all = Vec::new();
+    while let Some(addr) = zones.alloc(UNIT_SIZE, Zone::Normal) {
+        let phys = zones.phys_addr(addr).unwrap();
+        assert!(phys < reserved.0 || reserved.0 + reserved.1 <= phys, "reserved memory is allocated");
+        all.push(addr);
+    }
+    assert_eq!(zones.used(None), zones.data_len(None));
+
+    // freed Dma memory serves the other zones too
+    zones.dealloc(dma.pop().unwrap(), UNIT_SIZE);
+    assert!(zones.alloc(UNIT_SIZE, Zone::Dma32).is_some());
+    assert!(zones.alloc(UNIT_SIZE, Zone::Dma).is_none());
+
+    for addr in dma.into_iter().chain(all) {
+        zones.dealloc(addr, UNIT_SIZE);
+    }
+    assert_eq!(zones.used(None), UNIT_SIZE);
+}
+
+#[test]
+fn test_zones_with_holes() {
+    let mem = page_vec(0x100000);
+    let base = mem.as_ptr() as usize;
+    let mut zones = BuddyZones::new();
+
+    // two ranges far apart in physical memory, and one too small to be managed
+    unsafe {
+        zones.add_range(base, 0x1_2000_0000, 0x80000, &[]);
+        zones.add_range(base + 0x80000, 0x0200_0000, 0x7e000, &[]);
+        zones.add_range(base + 0xfe000, 0x0300_0000, 0x2000, &[]);
+    }
+    let kinds: Vec<_> = zones.ranges().map(|x| x.zone()).collect();
+    assert_eq!(kinds, [Zone::Normal, Zone::Dma32]);
+
+    let high = zones.alloc(0x10000, Zone::Normal).unwrap();
+    assert!(zones.phys_addr(high).unwrap() >= Zone::Dma32.limit());
+    let low = zones.alloc(0x10000, Zone::Dma32).unwrap();
+    assert!((0x0200_0000..0x0207_e000).contains(&zones.phys_addr(low).unwrap()));
+    assert!(zones.alloc(0x10000, Zone::Dma).is_none());
+
+    zones.dealloc(high, 0x10000);
+    zones.dealloc(low, 0x10000);
+    assert_eq!(zones.used(None), 0);
+}
+
+#[test]
+fn test_zones_alloc_aligned_physically() {
+    let mem = page_vec(0x100000);
+    let mut zones = BuddyZones::new();
+    unsafe { zones.add_range(mem.as_ptr() as usize, 0x0100_3000, 0x100000, &[]); }
+
+    let mut live = Vec::new();
+    while let Some(addr) = zones.alloc_aligned(0x3000, 0x10000, Zone::Normal) {
+        assert_eq!(zones.phys_addr(addr).unwrap() % 0x10000, 0);
+        live.push(addr);
+    }
+    // every 64 KiB boundary from 0x01010000 to 0x010f0000. the one at 0x01100000 is in the
+    // last 3 units, which are no block of 4 units.
+    assert_eq!(live.len(), 15);
+    assert_eq!(zones.used(None), live.len() * 0x3000);
+
+    for addr in live {
+        zones.add_free_range(addr, 0x3000);
+    }
+    assert_eq!(zones.used(None), 0);
+}
+
+#[test]
+fn test_zones_alloc_aligned_virt() {
+    let mem = page_vec(0x100000);
+    let mut zones = BuddyZones::new();
+    unsafe { zones.add_range(mem.as_ptr() as usize, 0x0100_3000, 0x100000, &[]); }
+
+    // the address is aligned, not the physical one
+    let mut live = Vec::new();
+    while let Some(addr) = zones.alloc_aligned_virt(0x3000, 0x10000, Zone::Normal) {
+        assert_eq!(addr % 0x10000, 0);
+        live.push(addr);
+    }
+    assert!(!live.is_empty());
+    assert_eq!(zones.used(None), live.len() * 0x3000);
+
+    for addr in live {
+        zones.add_free_range(addr, 0x3000);
+    }
+    assert_eq!(zones.used(None), 0);
+}
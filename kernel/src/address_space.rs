@@ -0,0 +1,215 @@
+use core::arch::x86_64::__cpuid;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use num_iter::range_step;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::structures::paging::page_table::PageTableEntry;
+
+use crate::frame::FrameFlags;
+use crate::memory::{PAGE_SIZE, alloc_zero, deallocate, get_table, phys_to_virt, put_page, set_frame_flags, virt_to_phys};
+use crate::page_mapper::{KernelTables, PageMapper, PageSize};
+
+pub use crate::page_mapper::MapError;
+
+// the lower half except PML4[0], which holds the dynamic memory
+pub const USER_START: u64 = 0x0000_0080_0000_0000;
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+// pages allocated by `AddressSpace::alloc_pages` grow upward from here
+pub const USER_HEAP_START: u64 = 0x0000_0100_0000_0000;
+
+const CPUID_EDX_NX: u32 = 1 << 20;
+
+const USER_PML4_ENTRIES: Range<usize> = 1..256;
+const KERNEL_PML4_ENTRIES: Range<usize> = 256..512;
+
+// whether EFER.NXE is enabled, so that NO_EXECUTE is not a reserved bit
+static NO_EXECUTE: AtomicBool = AtomicBool::new(false);
+
+// page tables of a user process. the dynamic memory and the kernel half are shared
+// with the kernel page table, and are not accessible from ring 3.
+pub struct AddressSpace {
+    pml4: *mut PageTable,
+    heap_end: u64,
+}
+
+unsafe impl Send for AddressSpace {}
+
+// Safety: must be called once at boot, before any address space is created
+pub unsafe fn init_address_space() {
+    if __cpuid(0x8000_0001).edx & CPUID_EDX_NX != 0 {
+        unsafe {
+            Efer::update(|x| x.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+        NO_EXECUTE.store(true, Ordering::Relaxed);
+    }
+}
+
+// whether NO_EXECUTE can be set in the page tables
+pub(crate) fn no_execute_enabled() -> bool {
+    NO_EXECUTE.load(Ordering::Relaxed)
+}
+
+impl AddressSpace {
+    pub fn new() -> Option<Self> {
+        let pml4 = alloc_zero(PAGE_SIZE as usize)? as *mut PageTable;
+        set_frame_flags(pml4 as usize, PAGE_SIZE as usize, FrameFlags::PAGE_TABLE);
+
+        let kernel = get_table();
+        let table = unsafe { &mut *pml4 };
+        table[0] = kernel[0].clone();
+        for idx in KERNEL_PML4_ENTRIES {
+            table[idx] = kernel[idx].clone();
+        }
+
+        Some(Self {
+            pml4,
+            heap_end: USER_HEAP_START,
+        })
+    }
+
+    // physical address to load in CR3
+    pub fn page_table(&self) -> PhysAddr {
+        virt_to_phys(VirtAddr::from_ptr(self.pml4))
+    }
+
+    // map a new zeroed page at `virt`, accessible from ring 3. returns the address the kernel
+    // accesses the page through. NO_EXECUTE is ignored if the cpu does not support it.
+    pub fn map_page(&mut self, virt: VirtAddr, flags: PageTableFlags) -> Result<VirtAddr, MapError> {
+        assert!(virt.is_aligned(PAGE_SIZE), "unaligned user page: {:#x}", virt.as_u64());
+        assert!((USER_START..USER_END).contains(&virt.as_u64()), "not a user address: {:#x}", virt.as_u64());
+
+        let page = alloc_zero(PAGE_SIZE as usize).ok_or(MapError::OutOfMemory)?;
+        set_frame_flags(page, PAGE_SIZE as usize, FrameFlags::USER);
+
+        let phys = virt_to_phys(VirtAddr::new(page as u64));
+        if let Err(err) = self.mapper().map(virt, phys, PageSize::Small, flags | PageTableFlags::USER_ACCESSIBLE) {
+            deallocate(page, PAGE_SIZE as usize);
+            return Err(err);
+        }
+        Ok(VirtAddr::new(page as u64))
+    }
+
+    // unmap the page at `virt` mapped by `map_page`, and drop the reference on it
+    pub fn unmap_page(&mut self, virt: VirtAddr) -> bool {
+        assert!((USER_START..USER_END).contains(&virt.as_u64()), "not a user address: {:#x}", virt.as_u64());
+
+        let Some((phys, _)) = self.mapper().unmap(virt) else {
+            return false;
+        };
+        put_page(phys_to_virt(phys).as_u64() as usize);
+        true
+    }
+
+    pub fn translate(&self, virt: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate(virt)
+    }
+
+    // map `count` new zeroed pages writable from ring 3 at the end of the heap,
+    // and returns the address of the first page
+    pub fn alloc_pages(&mut self, count: u64) -> Option<VirtAddr> {
+        let start = self.heap_end;
+        if count > (USER_END - start) / PAGE_SIZE {
+            return None;
+        }
+
+        for _ in 0..count {
+            self.map_page(VirtAddr::new(self.heap_end), PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).ok()?;
+            // pages mapped before failing are kept, and freed with the address space
+            self.heap_end += PAGE_SIZE;
+        }
+        Some(VirtAddr::new(start))
+    }
+
+    // the mapper of the tables of the address space, which is only used while it is borrowed
+    fn mapper(&self) -> PageMapper {
+        unsafe { PageMapper::new(self.pml4, KernelTables) }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let pml4 = unsafe { &*self.pml4 };
+        for idx in USER_PML4_ENTRIES {
+            free_entry(&pml4[idx], 3);
+        }
+        deallocate(self.pml4 as usize, PAGE_SIZE as usize);
+    }
+}
+
+// free the table `entry` refers to with everything below it, or drop the reference of the
+// address space on the page it refers to. huge pages are never mapped by `map_page`, so the
+// address space holds no reference on them, and they are left alone.
+fn free_entry(entry: &PageTableEntry, level: usize) {
+    if entry.is_unused() || (level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE)) {
+        return;
+    }
+
+    let addr = phys_to_virt(entry.addr());
+    if level > 0 {
+        let table: &PageTable = unsafe { &*addr.as_ptr() };
+        for sub in table.iter() {
+            free_entry(sub, level - 1);
+        }
+        deallocate(addr.as_u64() as usize, PAGE_SIZE as usize);
+    }
+    else {
+        put_page(addr.as_u64() as usize);
+    }
+}
+
+// whether [addr, addr + len) is mapped in the active page table and accessible from ring 3
+pub fn check_user_range(addr: VirtAddr, len: u64, write: bool) -> bool {
+    let Some(end) = addr.as_u64().checked_add(len) else {
+        return false;
+    };
+    if addr.as_u64() < USER_START || end > USER_END {
+        return false;
+    }
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let (frame, _) = Cr3::read();
+    let pml4: &PageTable = unsafe { &*phys_to_virt(frame.start_address()).as_ptr() };
+    range_step(addr.align_down(PAGE_SIZE).as_u64(), end, PAGE_SIZE)
+        .all(|page| page_flags(pml4, VirtAddr::new(page)).contains(required))
+}
+
+// flags of the page at `virt` combined with its tables, empty if not mapped
+fn page_flags(pml4: &PageTable, virt: VirtAddr) -> PageTableFlags {
+    let indices = [virt.p4_index(), virt.p3_index(), virt.p2_index(), virt.p1_index()];
+
+    let mut table = pml4;
+    let mut flags = PageTableFlags::all();
+    for (level, idx) in indices.into_iter().enumerate() {
+        let entry = &table[idx];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return PageTableFlags::empty();
+        }
+        flags &= entry.flags();
+
+        // the page itself, or a 1 GiB or 2 MiB page whose frame is no table
+        let huge = level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        if level + 1 == indices.len() || huge {
+            break;
+        }
+        table = unsafe { &*phys_to_virt(entry.addr()).as_ptr() };
+    }
+    flags
+}
+
+// Safety: `page_table` must be a PML4 sharing the kernel half, such as `AddressSpace::page_table`
+pub unsafe fn switch_page_table(page_table: PhysAddr) {
+    let (current, flags) = Cr3::read();
+    if current.start_address() != page_table {
+        unsafe {
+            Cr3::write(PhysFrame::containing_address(page_table), flags);
+        }
+    }
+}
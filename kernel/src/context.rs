@@ -1,3 +1,5 @@
+use crate::fpu::FpuState;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct Context {
@@ -25,6 +27,8 @@ pub struct Context {
     pub rflags: u64,
     pub rsp: u64,
     pub ss: u64,
+    // not touched by `switch_context`, saved and restored lazily on #NM
+    pub fpu: FpuState,
 }
 
 unsafe extern "C" {
@@ -38,6 +42,7 @@ impl Context {
             r15: 0, r14: 0, r13: 0, r12: 0, r11: 0, r10: 0, r9: 0, r8: 0,
             rsi: 0, rdi: 0, rdx: 0, rcx: 0, rbx: 0, rax: 0, rbp: 0,
             rip: 0, cs: 0, rflags: 0, rsp: 0, ss: 0,
+            fpu: FpuState::new(),
         }
     }
 }
@@ -0,0 +1,274 @@
+// parser for static ELF64 executables for x86_64
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    UnsupportedClass,
+    UnsupportedEncoding,
+    UnsupportedVersion,
+    NotExecutable,
+    UnsupportedMachine,
+    // needs an interpreter, which is not supported
+    NotStatic,
+    BadProgramHeaders,
+    BadSegment,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    ph_offset: usize,
+    ph_count: usize,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 {
+            return Err(ElfError::UnsupportedClass);
+        }
+        if data[5] != ELFDATA2LSB {
+            return Err(ElfError::UnsupportedEncoding);
+        }
+        if data[6] != EV_CURRENT || read_u32(data, 20) != EV_CURRENT as u32 {
+            return Err(ElfError::UnsupportedVersion);
+        }
+        if read_u16(data, 16) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18) != EM_X86_64 {
+            return Err(ElfError::UnsupportedMachine);
+        }
+
+        let ph_offset = read_u64(data, 32);
+        let ph_entry_size = read_u16(data, 54) as usize;
+        let ph_count = read_u16(data, 56) as usize;
+        let ph_end = ph_offset.checked_add((ph_count * PROGRAM_HEADER_SIZE) as u64);
+        if (ph_count > 0 && ph_entry_size != PROGRAM_HEADER_SIZE) || ph_end.is_none_or(|x| x > data.len() as u64) {
+            return Err(ElfError::BadProgramHeaders);
+        }
+
+        let elf = Self {
+            data,
+            entry: read_u64(data, 24),
+            ph_offset: ph_offset as usize,
+            ph_count,
+        };
+
+        for ph in elf.program_headers() {
+            match ph.p_type {
+                PT_INTERP => return Err(ElfError::NotStatic),
+                PT_LOAD => elf.check_segment(&ph)?,
+                _ => {}
+            }
+        }
+
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.ph_count).map(|idx| {
+            let base = self.ph_offset + idx * PROGRAM_HEADER_SIZE;
+            let data = &self.data[base..base + PROGRAM_HEADER_SIZE];
+            ProgramHeader {
+                p_type: read_u32(data, 0),
+                flags: read_u32(data, 4),
+                offset: read_u64(data, 8),
+                vaddr: read_u64(data, 16),
+                file_size: read_u64(data, 32),
+                mem_size: read_u64(data, 40),
+                align: read_u64(data, 48),
+            }
+        })
+    }
+
+    // contents of the segment in the file. the rest up to `mem_size` is zero.
+    // `ph` must be a PT_LOAD header of this file.
+    pub fn segment_data(&self, ph: &ProgramHeader) -> &'a [u8] {
+        &self.data[ph.offset as usize..(ph.offset + ph.file_size) as usize]
+    }
+
+    fn check_segment(&self, ph: &ProgramHeader) -> Result<(), ElfError> {
+        let file_end = ph.offset.checked_add(ph.file_size);
+        let mem_end = ph.vaddr.checked_add(ph.mem_size);
+        if file_end.is_none_or(|x| x > self.data.len() as u64) || mem_end.is_none() || ph.file_size > ph.mem_size {
+            return Err(ElfError::BadSegment);
+        }
+        Ok(())
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    struct TestSegment<'a> {
+        p_type: u32,
+        flags: u32,
+        vaddr: u64,
+        data: &'a [u8],
+        mem_size: u64,
+    }
+
+    // an executable with the segments in order right after the program headers
+    fn build_elf(entry: u64, segments: &[TestSegment]) -> Vec<u8> {
+        let mut elf = Vec::new();
+        elf.extend_from_slice(&ELF_MAGIC);
+        elf.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+        elf.resize(16, 0);
+        elf.extend_from_slice(&ET_EXEC.to_le_bytes());
+        elf.extend_from_slice(&EM_X86_64.to_le_bytes());
+        elf.extend_from_slice(&(EV_CURRENT as u32).to_le_bytes());
+        elf.extend_from_slice(&entry.to_le_bytes());
+        elf.extend_from_slice(&(HEADER_SIZE as u64).to_le_bytes());   // e_phoff
+        elf.extend_from_slice(&0u64.to_le_bytes());                   // e_shoff
+        elf.extend_from_slice(&0u32.to_le_bytes());                   // e_flags
+        elf.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+        elf.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        elf.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+        elf.extend_from_slice(&[0; 6]);                               // e_shentsize, e_shnum, e_shstrndx
+        assert_eq!(elf.len(), HEADER_SIZE);
+
+        let mut offset = HEADER_SIZE + segments.len() * PROGRAM_HEADER_SIZE;
+        for segment in segments {
+            elf.extend_from_slice(&segment.p_type.to_le_bytes());
+            elf.extend_from_slice(&segment.flags.to_le_bytes());
+            elf.extend_from_slice(&(offset as u64).to_le_bytes());
+            elf.extend_from_slice(&segment.vaddr.to_le_bytes());
+            elf.extend_from_slice(&segment.vaddr.to_le_bytes());       // p_paddr
+            elf.extend_from_slice(&(segment.data.len() as u64).to_le_bytes());
+            elf.extend_from_slice(&segment.mem_size.to_le_bytes());
+            elf.extend_from_slice(&0x1000u64.to_le_bytes());
+            offset += segment.data.len();
+        }
+        for segment in segments {
+            elf.extend_from_slice(segment.data);
+        }
+        elf
+    }
+
+    fn test_segments() -> [TestSegment<'static>; 2] {
+        [
+            TestSegment { p_type: PT_LOAD, flags: PF_R | PF_X, vaddr: 0x80_0000_0000, data: &[0x90, 0xc3], mem_size: 2 },
+            TestSegment { p_type: PT_LOAD, flags: PF_R | PF_W, vaddr: 0x80_0000_1000, data: &[1, 2, 3], mem_size: 0x2000 },
+        ]
+    }
+
+    #[test]
+    fn test_parse_executable() {
+        let image = build_elf(0x80_0000_0000, &test_segments());
+        let elf = Elf::parse(&image).unwrap();
+        assert_eq!(elf.entry(), 0x80_0000_0000);
+
+        let headers: Vec<ProgramHeader> = elf.program_headers().collect();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].flags, PF_R | PF_X);
+        assert_eq!(headers[1].vaddr, 0x80_0000_1000);
+        assert_eq!(headers[1].mem_size, 0x2000);
+        assert_eq!(elf.segment_data(&headers[0]), &[0x90, 0xc3]);
+        assert_eq!(elf.segment_data(&headers[1]), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_parse_bad_header() {
+        let image = build_elf(0, &test_segments());
+
+        assert_eq!(Elf::parse(&image[..HEADER_SIZE - 1]).err(), Some(ElfError::TooShort));
+
+        let mut bad = image.clone();
+        bad[1] = b'e';
+        assert_eq!(Elf::parse(&bad).err(), Some(ElfError::BadMagic));
+
+        let mut bad = image.clone();
+        bad[4] = 1;
+        assert_eq!(Elf::parse(&bad).err(), Some(ElfError::UnsupportedClass));
+
+        let mut bad = image.clone();
+        bad[16] = 3;    // ET_DYN
+        assert_eq!(Elf::parse(&bad).err(), Some(ElfError::NotExecutable));
+
+        let mut bad = image.clone();
+        bad[18] = 3;    // EM_386
+        assert_eq!(Elf::parse(&bad).err(), Some(ElfError::UnsupportedMachine));
+
+        // program headers cut off
+        let len = HEADER_SIZE + PROGRAM_HEADER_SIZE;
+        assert_eq!(Elf::parse(&image[..len]).err(), Some(ElfError::BadProgramHeaders));
+    }
+
+    #[test]
+    fn test_parse_bad_segment() {
+        let image = build_elf(0, &[
+            TestSegment { p_type: PT_LOAD, flags: PF_R, vaddr: 0x1000, data: &[0; 16], mem_size: 8 },
+        ]);
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadSegment));
+
+        let image = build_elf(0, &[
+            TestSegment { p_type: PT_LOAD, flags: PF_R, vaddr: u64::MAX - 4, data: &[0; 16], mem_size: 16 },
+        ]);
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadSegment));
+
+        // segment data cut off
+        let image = build_elf(0, &test_segments());
+        assert_eq!(Elf::parse(&image[..image.len() - 1]).err(), Some(ElfError::BadSegment));
+    }
+
+    #[test]
+    fn test_parse_dynamic() {
+        let image = build_elf(0, &[
+            TestSegment { p_type: PT_INTERP, flags: PF_R, vaddr: 0, data: b"/lib/ld.so\0", mem_size: 11 },
+        ]);
+        assert_eq!(Elf::parse(&image).err(), Some(ElfError::NotStatic));
+    }
+}
@@ -0,0 +1,156 @@
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+use crate::{println, task};
+
+// large enough for x87, SSE and AVX states in the standard XSAVE format
+pub const FPU_AREA_SIZE: usize = 1024;
+
+const CPUID_ECX_XSAVE: u32 = 1 << 26;
+const CPUID_ECX_AVX: u32 = 1 << 28;
+
+const MXCSR_DEFAULT: u32 = 0x1f80;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+
+// state right after initialization, loaded when a task touches the fpu for the first time
+static mut INITIAL_STATE: FpuState = FpuState::new();
+
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+pub struct FpuState {
+    area: [u8; FPU_AREA_SIZE],
+    // false until the state is saved once
+    valid: bool,
+}
+
+impl FpuState {
+    pub const fn new() -> Self {
+        Self {
+            area: [0; FPU_AREA_SIZE],
+            valid: false,
+        }
+    }
+
+    // Safety: CR0.TS must be clear
+    pub unsafe fn save(&mut self) {
+        let area = self.area.as_mut_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+            } else {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+            }
+        }
+        self.valid = true;
+    }
+
+    // Safety: CR0.TS must be clear
+    pub unsafe fn restore(&self) {
+        let initial = &raw const INITIAL_STATE;
+        let area = if self.valid { self.area.as_ptr() } else { unsafe { (*initial).area.as_ptr() } };
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) area, options(nostack));
+            }
+        }
+    }
+}
+
+impl fmt::Debug for FpuState {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.debug_struct("FpuState").field("valid", &self.valid).finish_non_exhaustive()
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Safety: must be called once at boot, before any task uses the fpu
+pub unsafe fn init_fpu() {
+    unsafe {
+        Cr0::update(|x| {
+            x.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            x.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|x| x.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+
+        let features = __cpuid(1);
+        if features.ecx & CPUID_ECX_XSAVE != 0 {
+            Cr4::update(|x| x.insert(Cr4Flags::OSXSAVE));
+
+            let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+            if features.ecx & CPUID_ECX_AVX != 0 {
+                components |= XCr0Flags::AVX;
+            }
+            XCr0::write(components);
+
+            // ebx is the size of the area for the components enabled in XCR0
+            let size = __cpuid_count(0xd, 0).ebx as usize;
+            assert!(size <= FPU_AREA_SIZE, "xsave area is too large: {:#x}", size);
+            USE_XSAVE.store(true, Ordering::Relaxed);
+        }
+
+        asm!("fninit", options(nomem, nostack));
+        asm!("ldmxcsr [{}]", in(reg) &MXCSR_DEFAULT, options(nostack, readonly));
+        let initial = &raw mut INITIAL_STATE;
+        (*initial).save();
+    }
+
+    // the first task to touch the fpu takes the ownership in the #NM handler
+    set_task_switched(true);
+}
+
+pub fn uses_xsave() -> bool {
+    USE_XSAVE.load(Ordering::Relaxed)
+}
+
+// while CR0.TS is set, the next fpu/sse instruction raises #NM
+pub fn set_task_switched(on: bool) {
+    unsafe {
+        Cr0::update(|x| x.set(Cr0Flags::TASK_SWITCHED, on));
+    }
+}
+
+pub fn test_fpu(count: u64) {
+    fn worker(arg: u64) {
+        let id = task::current_task_id();
+        let pattern = 0x0123_4567_89ab_cdef ^ arg.wrapping_mul(0x1111_1111);
+
+        unsafe {
+            asm!("movq xmm0, {}", in(reg) pattern, options(nomem, nostack));
+        }
+
+        for round in 0..5 {
+            // burn cpu so that other tasks clobber the fpu registers in between
+            for _ in 0..2_000_000 {
+                core::hint::spin_loop();
+            }
+
+            let value: u64;
+            unsafe {
+                asm!("movq {}, xmm0", out(reg) value, options(nomem, nostack));
+            }
+            if value != pattern {
+                println!("task {} (arg={}) round #{}: xmm0 corrupted {:#018x} != {:#018x}", id, arg, round, value, pattern);
+                return;
+            }
+        }
+        println!("task {} (arg={}) kept its xmm0", id, arg);
+    }
+
+    for arg in 0..count {
+        if task::spawn("testfpu", worker, arg).is_none() {
+            println!("cannot spawn task #{}", arg);
+        }
+    }
+}
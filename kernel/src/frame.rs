@@ -0,0 +1,261 @@
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU16, AtomicU32, AtomicUsize, Ordering};
+use bitflags::bitflags;
+
+use crate::memory::{PAGE_SIZE, Zone};
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FrameFlags: u16 {
+        // never given by the allocator, e.g. the page tables of the dynamic memory
+        const RESERVED = 1 << 0;
+        // allocated by the kernel
+        const KERNEL = 1 << 1;
+        const PAGE_TABLE = 1 << 2;
+        const SLAB = 1 << 3;
+        // mapped in a user address space
+        const USER = 1 << 4;
+    }
+}
+
+// entry of the frame database for a physical page
+pub struct Frame {
+    refcount: AtomicU32,
+    flags: AtomicU16,
+    zone: Zone,
+    // free for the owner of the frame, e.g. to chain its frames in a list
+    link: AtomicUsize,
+}
+
+// frames counted by `FrameDb::stats`. a frame may be counted in several flags.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    pub reserved: usize,
+    pub kernel: usize,
+    pub page_table: usize,
+    pub slab: usize,
+    pub user: usize,
+    // frames with more than one reference
+    pub shared: usize,
+}
+
+// a Frame per page of a contiguous range of addresses, such as the dynamic memory window
+pub struct FrameDb {
+    base: usize,
+    frames: &'static [Frame],
+}
+
+impl Frame {
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags::from_bits_retain(self.flags.load(Ordering::Acquire))
+    }
+
+    pub fn zone(&self) -> Zone {
+        self.zone
+    }
+
+    pub fn link(&self) -> usize {
+        self.link.load(Ordering::Acquire)
+    }
+
+    pub fn set_link(&self, link: usize) {
+        self.link.store(link, Ordering::Release);
+    }
+
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_or(flags.bits(), Ordering::AcqRel);
+    }
+
+    pub fn remove_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_and(!flags.bits(), Ordering::AcqRel);
+    }
+
+    // take a reference, and returns the new count
+    pub fn get(&self) -> u32 {
+        let old = self.refcount.fetch_add(1, Ordering::AcqRel);
+        assert_ne!(old, 0, "reference to a free frame");
+        old + 1
+    }
+
+    // drop a reference, and returns the references left
+    pub fn put(&self) -> u32 {
+        let old = self.refcount.fetch_sub(1, Ordering::AcqRel);
+        assert_ne!(old, 0, "frame refcount underflow");
+        old - 1
+    }
+
+    fn set(&self, refcount: u32, flags: FrameFlags) {
+        self.refcount.store(refcount, Ordering::Release);
+        self.flags.store(flags.bits(), Ordering::Release);
+        self.link.store(0, Ordering::Release);
+    }
+}
+
+impl FrameDb {
+    // `frames` describe the pages from `base`, and are RESERVED until `set_free`.
+    // `zone_of` gives the zone of the page at an address.
+    pub fn new(base: usize, frames: &'static mut [MaybeUninit<Frame>], zone_of: impl Fn(usize) -> Zone) -> Self {
+        for (idx, frame) in frames.iter_mut().enumerate() {
+            frame.write(Frame {
+                refcount: AtomicU32::new(0),
+                flags: AtomicU16::new(FrameFlags::RESERVED.bits()),
+                zone: zone_of(base + idx * PAGE_SIZE as usize),
+                link: AtomicUsize::new(0),
+            });
+        }
+
+        // Safety: every frame is initialized
+        let frames = unsafe { &*(frames as *mut [MaybeUninit<Frame>] as *const [Frame]) };
+        Self { base, frames }
+    }
+
+    // frame of the page at `addr`
+    pub fn frame(&self, addr: usize) -> Option<&'static Frame> {
+        let idx = addr.checked_sub(self.base)? / PAGE_SIZE as usize;
+        self.frames.get(idx)
+    }
+
+    // frames of the pages covering [addr, addr + len)
+    pub fn frames(&self, addr: usize, len: usize) -> &'static [Frame] {
+        let page = PAGE_SIZE as usize;
+        let start = (addr - self.base) / page;
+        let end = (addr + len - self.base).div_ceil(page);
+        &self.frames[start..end]
+    }
+
+    // pages managed by the allocator, which are free
+    pub fn set_free(&self, addr: usize, len: usize) {
+        for frame in self.frames(addr, len) {
+            frame.set(0, FrameFlags::empty());
+        }
+    }
+
+    // pages just allocated, with a reference each
+    pub fn mark_allocated(&self, addr: usize, len: usize, flags: FrameFlags) {
+        for frame in self.frames(addr, len) {
+            assert!(frame.refcount() == 0 && !frame.flags().contains(FrameFlags::RESERVED), "allocating a used frame at {:#x}", addr);
+            frame.set(1, flags);
+        }
+    }
+
+    // pages about to be freed, with their last reference or none
+    pub fn mark_freed(&self, addr: usize, len: usize) {
+        for frame in self.frames(addr, len) {
+            assert!(frame.refcount() <= 1, "freeing a shared frame at {:#x}", addr);
+            assert!(!frame.flags().contains(FrameFlags::RESERVED), "freeing a reserved frame at {:#x}", addr);
+            frame.set(0, FrameFlags::empty());
+        }
+    }
+
+    pub fn insert_flags(&self, addr: usize, len: usize, flags: FrameFlags) {
+        for frame in self.frames(addr, len) {
+            frame.insert_flags(flags);
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        let mut stats = FrameStats { total: self.frames.len(), ..FrameStats::default() };
+        for frame in self.frames {
+            let flags = frame.flags();
+            if frame.refcount() == 0 && !flags.contains(FrameFlags::RESERVED) {
+                stats.free += 1;
+            }
+            if frame.refcount() > 1 {
+                stats.shared += 1;
+            }
+            let counters = [
+                (FrameFlags::RESERVED, &mut stats.reserved),
+                (FrameFlags::KERNEL, &mut stats.kernel),
+                (FrameFlags::PAGE_TABLE, &mut stats.page_table),
+                (FrameFlags::SLAB, &mut stats.slab),
+                (FrameFlags::USER, &mut stats.user),
+            ];
+            for (flag, count) in counters {
+                if flags.contains(flag) {
+                    *count += 1;
+                }
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    const BASE: usize = 0x0020_0000;
+    const PAGE: usize = PAGE_SIZE as usize;
+
+    fn create_db(count: usize) -> FrameDb {
+        let frames = (0..count).map(|_| MaybeUninit::uninit()).collect::<Vec<_>>();
+        FrameDb::new(BASE, Box::leak(frames.into_boxed_slice()), |addr| {
+            if addr < BASE + 4 * PAGE { Zone::Dma } else { Zone::Normal }
+        })
+    }
+
+    #[test]
+    fn test_frame_db_marks_and_counts() {
+        let db = create_db(16);
+        assert_eq!(db.stats().reserved, 16);
+        assert_eq!(db.frame(BASE + 3 * PAGE).unwrap().zone(), Zone::Dma);
+        assert_eq!(db.frame(BASE + 4 * PAGE).unwrap().zone(), Zone::Normal);
+        assert!(db.frame(BASE - 1).is_none());
+        assert!(db.frame(BASE + 16 * PAGE).is_none());
+
+        db.set_free(BASE + 2 * PAGE, 14 * PAGE);
+        db.mark_allocated(BASE + 4 * PAGE, 3 * PAGE - 1, FrameFlags::KERNEL);
+        db.insert_flags(BASE + 5 * PAGE, PAGE, FrameFlags::SLAB);
+        let stats = db.stats();
+        assert_eq!((stats.total, stats.free, stats.reserved), (16, 11, 2));
+        assert_eq!((stats.kernel, stats.slab, stats.user), (3, 1, 0));
+
+        db.mark_freed(BASE + 4 * PAGE, 3 * PAGE);
+        assert_eq!(db.stats().free, 14);
+        assert_eq!(db.frame(BASE + 5 * PAGE).unwrap().flags(), FrameFlags::empty());
+    }
+
+    #[test]
+    fn test_frame_refcount() {
+        let db = create_db(4);
+        db.set_free(BASE, 4 * PAGE);
+        db.mark_allocated(BASE + PAGE, PAGE, FrameFlags::USER);
+
+        let frame = db.frame(BASE + PAGE).unwrap();
+        assert_eq!(frame.get(), 2);
+        assert_eq!(db.stats().shared, 1);
+        assert_eq!(frame.put(), 1);
+        assert_eq!(frame.put(), 0);
+
+        // the last reference is dropped before the page is freed
+        db.mark_freed(BASE + PAGE, PAGE);
+        assert_eq!(db.stats().free, 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "allocating a used frame")]
+    fn test_frame_allocated_twice() {
+        let db = create_db(4);
+        db.set_free(BASE, 4 * PAGE);
+        db.mark_allocated(BASE, 2 * PAGE, FrameFlags::KERNEL);
+        db.mark_allocated(BASE + PAGE, PAGE, FrameFlags::KERNEL);
+    }
+
+    #[test]
+    #[should_panic(expected = "freeing a shared frame")]
+    fn test_frame_freed_while_shared() {
+        let db = create_db(4);
+        db.set_free(BASE, 4 * PAGE);
+        db.mark_allocated(BASE, PAGE, FrameFlags::USER);
+        db.frame(BASE).unwrap().get();
+        db.mark_freed(BASE, PAGE);
+    }
+}
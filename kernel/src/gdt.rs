@@ -10,6 +10,9 @@ pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
 pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
 pub const KERNEL_TSS_SELECTOR: u16 = 0x18;
+// user data comes right before user code, as `sysret` expects
+pub const USER_DATA_SELECTOR: u16 = 0x28 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x30 | 3;
 
 const STACK_SIZE: usize = 8192;
 
@@ -19,27 +22,31 @@ struct Selectors {
     tss: SegmentSelector,
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            VirtAddr::from_ptr(&raw mut STACK) + STACK_SIZE as u64
-        };
-        tss
-    };
+// mutable, since the kernel stack for interrupts from ring 3 changes on every task switch
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
+lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code = gdt.append(Descriptor::kernel_code_segment());
         let data = gdt.append(Descriptor::kernel_data_segment());
-        let tss = gdt.append(Descriptor::tss_segment(&TSS));
+        let tss = gdt.append(unsafe { Descriptor::tss_segment_unchecked(&raw const TSS) });
+        let user_data = gdt.append(Descriptor::user_data_segment());
+        let user_code = gdt.append(Descriptor::user_code_segment());
+        assert_eq!(user_data.0, USER_DATA_SELECTOR);
+        assert_eq!(user_code.0, USER_CODE_SELECTOR);
         (gdt, Selectors { code, data, tss })
     };
 }
 
 pub unsafe fn init_gdt() {
     unsafe {
+        let tss = &raw mut TSS;
+        (*tss).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            VirtAddr::from_ptr(&raw mut STACK) + STACK_SIZE as u64
+        };
+
         GDT.0.load();
         DS::set_reg(GDT.1.data);
         ES::set_reg(GDT.1.data);
@@ -50,3 +57,11 @@ pub unsafe fn init_gdt() {
         load_tss(GDT.1.tss);
     }
 }
+
+// stack the cpu switches to on an interrupt or exception from ring 3
+pub fn set_kernel_stack(top: VirtAddr) {
+    let tss = &raw mut TSS;
+    unsafe {
+        (*tss).privilege_stack_table[0] = top;
+    }
+}
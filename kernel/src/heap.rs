@@ -0,0 +1,257 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt::Write;
+use core::ptr::{NonNull, null_mut};
+use arrayvec::ArrayVec;
+use lazy_static::lazy_static;
+
+use slab_alloc::{SIZE_CLASSES, SIZE_CLASS_NAMES, SizeClassAllocator, SlabCorruption, SlabStats};
+
+use crate::println;
+use crate::irq_mutex::IrqMutex;
+use crate::memory::{self, BuddyPages, PAGE_SIZE};
+use crate::terminal::ColorCode;
+
+// requests up to the largest of SIZE_CLASSES are served by the slabs, and the others by the
+// buddy allocator
+struct Heap {
+    classes: SizeClassAllocator<BuddyPages>,
+    info: HeapInfo,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapInfo {
+    // bytes taken from the buddy allocator for large requests
+    pub large_len: usize,
+    pub large_count: usize,
+    pub failures: usize,
+}
+
+// a slab cache of the kernel listed by `slab_list`, besides the size classes of the heap
+#[derive(Clone, Copy)]
+pub struct SlabCache {
+    pub name: &'static str,
+    pub stats: fn() -> SlabStats,
+    pub verify: fn() -> Result<(), SlabCorruption>,
+    // release the cached empty pages, and returns how many
+    pub shrink: fn() -> usize,
+}
+
+const MAX_SLAB_CACHES: usize = 32;
+
+// empty pages each size class keeps, see `SlabAllocator::set_empty_limit`
+const HEAP_EMPTY_PAGES: usize = 1;
+
+pub struct KernelAllocator;
+
+#[cfg(not(test))]
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+lazy_static! {
+    static ref HEAP: IrqMutex<Heap> = IrqMutex::new(Heap::new());
+}
+
+static ALLOC_ERROR_HANDLER: IrqMutex<fn(Layout)> = IrqMutex::new(print_alloc_error);
+
+static SLAB_CACHES: IrqMutex<ArrayVec<SlabCache, MAX_SLAB_CACHES>> = IrqMutex::new(ArrayVec::new_const());
+
+impl Heap {
+    fn new() -> Self {
+        let mut classes = SizeClassAllocator::new(BuddyPages);
+        classes.set_empty_limit(HEAP_EMPTY_PAGES);
+        Self {
+            classes,
+            info: HeapInfo {
+                large_len: 0,
+                large_count: 0,
+                failures: 0,
+            },
+        }
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = match SizeClassAllocator::<BuddyPages>::class_index(layout) {
+            Some(_) => self.classes.alloc(layout).map(NonNull::as_ptr),
+            None => self.alloc_large(layout),
+        };
+        ptr.unwrap_or(null_mut())
+    }
+
+    // Safety: `ptr` must be allocated by `alloc` with the same `layout`
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new(ptr).expect("deallocating a null pointer");
+        match SizeClassAllocator::<BuddyPages>::class_index(layout) {
+            Some(_) => unsafe {
+                self.classes.dealloc(ptr, layout);
+            },
+            None => {
+                if layout.align() > PAGE_SIZE as usize {
+                    memory::deallocate_aligned(ptr.as_ptr() as usize, layout.size());
+                } else {
+                    memory::deallocate(ptr.as_ptr() as usize, layout.size());
+                }
+                self.info.large_len -= large_len(layout);
+                self.info.large_count -= 1;
+            }
+        }
+    }
+
+    fn alloc_large(&mut self, layout: Layout) -> Option<*mut u8> {
+        // buddy blocks are aligned in PAGE_SIZE, but not always in their size
+        let addr = if layout.align() > PAGE_SIZE as usize {
+            memory::allocate_aligned_virt(layout.size(), layout.align())?
+        } else {
+            memory::allocate(layout.size())?
+        };
+        self.info.large_len += large_len(layout);
+        self.info.large_count += 1;
+        Some(addr as *mut u8)
+    }
+}
+
+// over-aligned allocations are rounded to pages, the others to buddy blocks
+fn large_len(layout: Layout) -> usize {
+    if layout.align() > PAGE_SIZE as usize {
+        layout.size().next_multiple_of(PAGE_SIZE as usize)
+    } else {
+        memory::block_len(layout.size())
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut ptr = HEAP.lock().alloc(layout);
+        if ptr.is_null() && shrink_slabs() > 0 {
+            ptr = HEAP.lock().alloc(layout);
+        }
+
+        if ptr.is_null() {
+            HEAP.lock().info.failures += 1;
+            // the handler runs without the lock, so that it can look at the heap
+            let handler = *ALLOC_ERROR_HANDLER.lock();
+            handler(layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe {
+            HEAP.lock().dealloc(ptr, layout);
+        }
+    }
+}
+
+// called when an allocation fails, before `alloc` returns null
+pub fn set_alloc_error_handler(handler: fn(Layout)) {
+    *ALLOC_ERROR_HANDLER.lock() = handler;
+}
+
+pub fn heap_info() -> HeapInfo {
+    HEAP.lock().info
+}
+
+// `class` is an index in SIZE_CLASSES
+pub fn size_class_stats(class: usize) -> SlabStats {
+    HEAP.lock().classes.stats(class)
+}
+
+// returns false if there are already MAX_SLAB_CACHES caches
+pub fn register_slab_cache(cache: SlabCache) -> bool {
+    SLAB_CACHES.lock().try_push(cache).is_ok()
+}
+
+// memory pressure path: ask the heap and every registered cache to release their empty pages,
+// and returns how many pages were released
+pub fn shrink_slabs() -> usize {
+    let mut count = HEAP.lock().classes.shrink();
+
+    let caches = SLAB_CACHES.lock().clone();
+    for cache in caches {
+        count += (cache.shrink)();
+    }
+    count
+}
+
+// check the redzones and the poison of every slab object of the heap and the registered caches,
+// and call `f` with the name of each cache and the result
+pub fn verify_slabs<F: FnMut(&str, Result<(), SlabCorruption>)>(mut f: F) {
+    let result = HEAP.lock().classes.verify();
+    f("heap", result);
+
+    let caches = SLAB_CACHES.lock().clone();
+    for cache in caches {
+        f(cache.name, (cache.verify)());
+    }
+}
+
+// call `f` with the name and the stats of every size class of the heap and every registered cache.
+// no lock is held while `f` runs.
+pub fn slab_list<F: FnMut(&str, &SlabStats)>(mut f: F) {
+    for (class, name) in SIZE_CLASS_NAMES.iter().enumerate() {
+        f(name, &size_class_stats(class));
+    }
+
+    let caches = SLAB_CACHES.lock().clone();
+    for cache in caches {
+        f(cache.name, &(cache.stats)());
+    }
+}
+
+pub fn print_alloc_error(layout: Layout) {
+    println!(color: ColorCode::ERROR, "heap allocation failed: size={:#x}, align={:#x}", layout.size(), layout.align());
+    print_heap_info();
+}
+
+pub fn print_heap_info() {
+    let info = heap_info();
+    let memory = memory::allocator_size_info();
+
+    println!("dynmem used          : {:#x} / {:#x}", memory.used, memory.len);
+    for class in 0..SIZE_CLASSES.len() {
+        let stats = size_class_stats(class);
+        if stats.allocs > 0 {
+            println!("heap {:>4} bytes      : {} objects, {} pages", stats.object_size, stats.live, stats.pages << stats.order);
+        }
+    }
+    println!("heap large           : {} blocks, {:#x} bytes", info.large_count, info.large_len);
+    println!("heap failures        : {}", info.failures);
+}
+
+pub fn test_heap() {
+    use alloc::boxed::Box;
+    use alloc::collections::BTreeMap;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    let boxed = Box::new(0x1234u64);
+    let mut vec: Vec<u32> = (0..2000).collect();
+    vec.retain(|x| x % 3 == 0);
+    let mut string = String::new();
+    let mut map = BTreeMap::new();
+    for (idx, x) in vec.iter().enumerate().take(100) {
+        map.insert(*x, idx);
+        write!(string, "{} ", x).unwrap();
+    }
+
+    let ok = *boxed == 0x1234
+        && vec.len() == 667
+        && vec.iter().all(|x| x % 3 == 0)
+        && map.len() == 100
+        && map.get(&297) == Some(&99)
+        && string.starts_with("0 3 6 9 ");
+    println!("box, vec, string and btreemap: {}", if ok { "ok" } else { "FAILED" });
+
+    // larger than the size classes and aligned in more than a page
+    let layout = Layout::from_size_align(3 * PAGE_SIZE as usize, 4 * PAGE_SIZE as usize).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    let ok = !ptr.is_null() && (ptr as usize).is_multiple_of(layout.align());
+    if !ptr.is_null() {
+        unsafe {
+            core::ptr::write_bytes(ptr, 0xa5, layout.size());
+            alloc::alloc::dealloc(ptr, layout);
+        }
+    }
+    println!("page-multiple alignment: {}", if ok { "ok" } else { "FAILED" });
+
+    print_heap_info();
+}
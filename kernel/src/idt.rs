@@ -1,9 +1,11 @@
 use core::fmt;
 use lazy_static::lazy_static;
+use x86_64::PrivilegeLevel;
 use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
-use crate::gdt;
+use crate::{gdt, task, println};
+use crate::terminal::ColorCode;
 use crate::pic::Irq;
 use crate::pit::timer_int_handler;
 use crate::keyboard::keyboard_int_handler;
@@ -56,6 +58,7 @@ pub unsafe fn init_idt() {
 }
 
 extern "x86-interrupt" fn divide_error_int_handler(stack_frame: InterruptStackFrame) {
+    kill_user_task(format_args!("#DE"), &stack_frame);
     panic!("#DE {}", StackFrame(stack_frame));
 }
 
@@ -80,14 +83,21 @@ extern "x86-interrupt" fn bound_range_exceeded_int_handler(stack_frame: Interrup
 }
 
 extern "x86-interrupt" fn invalid_opcode_int_handler(stack_frame: InterruptStackFrame) {
+    kill_user_task(format_args!("#UD"), &stack_frame);
     panic!("#UD {}", StackFrame(stack_frame));
 }
 
-extern "x86-interrupt" fn device_not_available_int_handler(stack_frame: InterruptStackFrame) {
-    panic!("#NM {}", StackFrame(stack_frame));
+extern "x86-interrupt" fn device_not_available_int_handler(_stack_frame: InterruptStackFrame) {
+    task::fpu_trap();
 }
 
+// an overflowing task faults on its stack guard page, and fails to push the #PF frame
+// there. so it is reported here, running on the IST stack.
 extern "x86-interrupt" fn double_fault_int_handler(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+    let addr = Cr2::read_raw();
+    if let Some((id, name)) = task::stack_guard_owner(addr) {
+        panic!("#DF: stack overflow in task {} ({}) access={:#018x} {}", id, name, addr, StackFrame(stack_frame));
+    }
     panic!("#DF:{:#018x} {}", error_code, StackFrame(stack_frame));
 }
 
@@ -100,15 +110,22 @@ extern "x86-interrupt" fn segment_not_present_int_handler(stack_frame: Interrupt
 }
 
 extern "x86-interrupt" fn stack_segment_fault_int_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    kill_user_task(format_args!("#SS:{:#018x}", error_code), &stack_frame);
     panic!("#SS:{:#018x} {}", error_code, StackFrame(stack_frame));
 }
 
 extern "x86-interrupt" fn general_protection_fault_int_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    kill_user_task(format_args!("#GP:{:#018x}", error_code), &stack_frame);
     panic!("#GP:{:#018x} {}", error_code, StackFrame(stack_frame));
 }
 
 extern "x86-interrupt" fn page_fault_int_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-    panic!("#PF:{} access={:#018x} {}", PFCode(error_code), Cr2::read_raw(), StackFrame(stack_frame));
+    let addr = Cr2::read_raw();
+    kill_user_task(format_args!("#PF:{} access={:#018x}", PFCode(error_code), addr), &stack_frame);
+    if let Some((id, name)) = task::stack_guard_owner(addr) {
+        panic!("#PF:{} stack overflow in task {} ({}) access={:#018x} {}", PFCode(error_code), id, name, addr, StackFrame(stack_frame));
+    }
+    panic!("#PF:{} access={:#018x} {}", PFCode(error_code), addr, StackFrame(stack_frame));
 }
 
 extern "x86-interrupt" fn x87_floating_point_int_handler(stack_frame: InterruptStackFrame) {
@@ -143,6 +160,15 @@ extern "x86-interrupt" fn unknown_int_handler(stack_frame: InterruptStackFrame)
     panic!("#UNKNOWN {}", StackFrame(stack_frame));
 }
 
+// a fault in ring 3 terminates the task instead of the whole kernel
+fn kill_user_task(fault: fmt::Arguments, stack_frame: &InterruptStackFrame) {
+    if stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3 {
+        println!(color: ColorCode::ERROR, "task {} killed by {} ip={:#018x}, sp={:#018x}",
+            task::current_task_id(), fault, stack_frame.instruction_pointer.as_u64(), stack_frame.stack_pointer.as_u64());
+        task::exit();
+    }
+}
+
 struct StackFrame(InterruptStackFrame);
 struct PFCode(PageFaultErrorCode);
 
@@ -2,10 +2,10 @@ use lazy_static::lazy_static;
 
 use crate::irq_mutex::IrqMutex;
 use crate::ring_buffer::RingBuffer;
+use crate::wait_queue::WaitQueue;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InterruptMessage {
-    Timer(),
     Keyboard(u8),
 }
 
@@ -13,18 +13,32 @@ const BUFFER_SIZE: usize = 4096;
 
 lazy_static! {
     static ref QUEUE: IrqMutex<RingBuffer<InterruptMessage, BUFFER_SIZE>> = {
-        const EMPTY: InterruptMessage = InterruptMessage::Timer();
+        const EMPTY: InterruptMessage = InterruptMessage::Keyboard(0);
         IrqMutex::new(RingBuffer::new_with(EMPTY))
     };
 }
 
+static WAIT_QUEUE: WaitQueue = WaitQueue::new();
+
 pub fn intmsg_push(msg: InterruptMessage) {
     let mut queue = QUEUE.lock();
     if queue.len() < BUFFER_SIZE {
         queue.try_push(msg);
     }
+    drop(queue);
+
+    WAIT_QUEUE.wake_one();
 }
 
 pub fn intmsg_pop() -> Option<InterruptMessage> {
     QUEUE.lock().try_pop()
 }
+
+pub fn intmsg_wait() -> InterruptMessage {
+    let mut msg = None;
+    WAIT_QUEUE.wait_until(|| {
+        msg = intmsg_pop();
+        msg.is_some()
+    });
+    msg.unwrap()
+}
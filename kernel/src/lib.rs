@@ -3,6 +3,8 @@
 #![feature(abi_x86_interrupt)]
 #![deny(unsafe_op_in_unsafe_fn)]
 
+extern crate alloc;
+
 pub mod fixed_writer;
 pub mod irq_mutex;
 pub mod serial;
@@ -15,13 +17,22 @@ pub mod pit;
 pub mod keyboard;
 pub mod ring_buffer;
 pub mod memory;
+pub mod frame;
+pub mod heap;
+pub mod fpu;
 pub mod context;
+pub mod address_space;
+pub mod page_mapper;
+pub mod elf;
+pub mod programs;
+pub mod user;
+pub mod syscall;
 pub mod task;
+pub mod sched_policy;
+pub mod wait_queue;
 pub mod shell;
 
-use x86_64::instructions::interrupts;
-
-use crate::interrupt_queue::{InterruptMessage, intmsg_pop};
+use crate::interrupt_queue::{InterruptMessage, intmsg_wait};
 
 #[unsafe(no_mangle)]
 pub extern "C" fn kmain() -> ! {
@@ -38,9 +49,21 @@ pub extern "C" fn kmain() -> ! {
         idt::init_idt();
         log!("idt initialized");
 
+        fpu::init_fpu();
+        log!("fpu initialized ({})", if fpu::uses_xsave() { "xsave" } else { "fxsave" });
+
         memory::init_memory();
         log!("page initialized");
 
+        address_space::init_address_space();
+        log!("address space initialized");
+
+        task::init_task();
+        log!("task initialized");
+
+        syscall::init_syscall();
+        log!("syscall initialized");
+
         pic::init_pic();
         log!("pic initialized");
 
@@ -57,26 +80,11 @@ pub extern "C" fn kmain() -> ! {
 
     log!("done");
 
-    let mut buffer = [0u8; terminal::INPUT_MAXSIZE];
-
-    shell::prompt();
+    task::spawn("shell", shell::shell_main, 0).expect("cannot spawn the shell task");
 
     loop {
-        interrupts::disable();
-        if let Some(msg) = intmsg_pop() {
-            interrupts::enable();
-            match msg {
-                InterruptMessage::Timer() => pit::timer_handler(),
-                InterruptMessage::Keyboard(data) => keyboard::keyboard_handler(data),
-            }
-
-            if let Ok(input) = terminal::getline(&mut buffer) {
-                shell::input_line(input);
-                shell::prompt();
-            }
-        }
-        else {
-            interrupts::enable_and_hlt();
+        match intmsg_wait() {
+            InterruptMessage::Keyboard(data) => keyboard::keyboard_handler(data),
         }
     }
 }
@@ -1,15 +1,23 @@
+use arrayvec::ArrayVec;
+use core::mem::size_of;
+use core::ptr::NonNull;
+use core::slice::from_raw_parts_mut;
 use lazy_static::lazy_static;
 use num_enum::{TryFromPrimitive, IntoPrimitive};
 use num_integer::div_ceil;
 use num_iter::range_step;
+use spin::Once;
 use x86_64::instructions::tlb;
 use x86_64::structures::paging::page_table::PageTableEntry;
 use x86_64::{VirtAddr, PhysAddr};
 use x86_64::structures::paging::{PageTable, PageTableFlags};
 
-use buddyblock::{BuddyBlock, BuddyBlockInfo};
+use buddyblock::{BuddyBlockInfo, BuddyZones, MAX_RANGES};
+pub use buddyblock::{DeallocError, Zone, ZONES};
+use slab_alloc::PageAllocator;
 
 use crate::log;
+use crate::frame::{Frame, FrameDb, FrameFlags, FrameStats};
 use crate::irq_mutex::IrqMutex;
 use crate::terminal::ColorCode;
 
@@ -49,10 +57,18 @@ struct MemoryData {
     total_len: usize,
     page_table_len: usize,
     buddy_len: usize,
-    buddyblock: BuddyBlock<'static>,
+    // a range per entry of the dynamic memory map
+    zones: BuddyZones<'static>,
 }
 
 pub struct AllocatorInfo {
+    pub ranges: ArrayVec<RangeInfo, MAX_RANGES>,
+    pub used: usize,
+}
+
+pub struct RangeInfo {
+    pub zone: Zone,
+    pub phys_addr: usize,
     pub buddy: BuddyBlockInfo,
     pub used: usize,
 }
@@ -81,10 +97,13 @@ lazy_static! {
         total_len: 0,
         page_table_len: 0,
         buddy_len: 0,
-        buddyblock: BuddyBlock::empty(),
+        zones: BuddyZones::new(),
     });
 }
 
+// a frame per page of the dynamic memory window, set up after the buddy allocator
+static FRAMES: Once<FrameDb> = Once::new();
+
 lazy_static! {
     static ref DYNMEM_MAP: MemoryMap = {
         const BUFSIZE: usize = 1024;
@@ -110,6 +129,7 @@ pub unsafe fn init_memory() {
     unsafe {
         init_dyn_page();
         init_dyn_alloc();
+        init_frames();
     }
 }
 
@@ -308,7 +328,6 @@ fn phys_to_virt_dynmem(phys: PhysAddr, map: MemoryMap, start_virt: u64) -> VirtA
     panic!("invalid dynmem physical address");
 }
 
-#[allow(dead_code)]
 fn virt_to_phys_kernel(virt: VirtAddr) -> PhysAddr {
     let addr = virt.as_u64() - KERNEL_START_VIRT + KERNEL_START_PHYS;
     PhysAddr::new(addr)
@@ -319,7 +338,20 @@ fn phys_to_virt_kernel(phys: PhysAddr) -> VirtAddr {
     VirtAddr::new(addr)
 }
 
-fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+// translate an address of the kernel image or the dynamic memory
+pub fn virt_to_phys(virt: VirtAddr) -> PhysAddr {
+    if virt.as_u64() < KERNEL_START_VIRT {
+        virt_to_phys_dynmem(virt, get_memory_map(), DYNMEM_START_VIRT)
+    }
+    else if virt.as_u64() < KERNEL_START_VIRT + (KSTACK_START_PHYS - KERNEL_START_PHYS) {
+        virt_to_phys_kernel(virt)
+    }
+    else {
+        panic!("invalid virtual address")
+    }
+}
+
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
     if phys.as_u64() >= DYNMEM_START_PHYS {
         phys_to_virt_dynmem(phys, get_memory_map(), DYNMEM_START_VIRT)
     }
@@ -331,36 +363,177 @@ fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
     }
 }
 
+// the entries of the dynamic memory map are managed as separate ranges, after the page
+// tables at the start of the dynmem window
 unsafe fn init_dyn_alloc() {
     let mut data = MEMORY_DATA.lock();
-    let start = (DYNMEM_START_VIRT as usize) + data.page_table_len;
-    let len = data.total_len - data.page_table_len;
+    let mut skip = data.page_table_len as u64;
+    let mut virt = DYNMEM_START_VIRT;
+
+    for entry in get_memory_map().entries {
+        let used = skip.min(entry.size);
+        skip -= used;
+        if used < entry.size {
+            let added = unsafe {
+                data.zones.add_range((virt + used) as usize, (entry.base + used) as usize, (entry.size - used) as usize, &[])
+            };
+            if !added {
+                log!(color: ColorCode::ERROR, "too many memory ranges, ignoring [{:#x}, {:#x})", entry.base, entry.base + entry.size);
+            }
+        }
+        virt += entry.size;
+    }
+    data.buddy_len = data.zones.ranges().map(|x| x.info().data_offset()).sum();
+}
+
+// the pages outside the buddy allocator stay reserved: the page tables of the window, the
+// metadata of the buddy allocator, and the pieces of the memory map too small to manage
+unsafe fn init_frames() {
+    let mut data = MEMORY_DATA.lock();
+    let page = PAGE_SIZE as usize;
+    let count = data.total_len / page;
+    let len = count * size_of::<Frame>();
+
+    let addr = data.zones.alloc_aligned(len, page, Zone::Normal).expect("no memory for the frame database");
+    let frames = unsafe { from_raw_parts_mut(addr as *mut _, count) };
+    let db = FrameDb::new(DYNMEM_START_VIRT as usize, frames, |virt| {
+        let phys = virt_to_phys_dynmem(VirtAddr::new(virt as u64), get_memory_map(), DYNMEM_START_VIRT);
+        Zone::of(phys.as_u64() as usize)
+    });
 
-    data.buddyblock = unsafe {
-        BuddyBlock::new(start, len)
-    };
-    data.buddy_len = data.buddyblock.info().data_offset();
+    for range in data.zones.ranges() {
+        db.set_free(range.info().data_addr(), range.info().data_len());
+    }
+    db.mark_allocated(addr, len, FrameFlags::KERNEL);
+    db.insert_flags(addr, len, FrameFlags::RESERVED);
+    db.insert_flags(DYNMEM_START_VIRT as usize, data.page_table_len, FrameFlags::PAGE_TABLE);
+    FRAMES.call_once(|| db);
+}
+
+// frame of the page of the dynamic memory at `addr`. None before the memory is initialized.
+pub fn frame(addr: usize) -> Option<&'static Frame> {
+    FRAMES.get()?.frame(addr)
+}
+
+pub fn frame_stats() -> FrameStats {
+    FRAMES.get().map(FrameDb::stats).unwrap_or_default()
+}
+
+// add `flags` to the frames of the pages in [addr, addr + len), e.g. FrameFlags::PAGE_TABLE
+pub fn set_frame_flags(addr: usize, len: usize, flags: FrameFlags) {
+    if let Some(db) = FRAMES.get() {
+        db.insert_flags(addr, len, flags);
+    }
+}
+
+// take a reference on the page at `addr`, which is allocated, e.g. to map it twice
+pub fn get_page(addr: usize) {
+    frame(addr).expect("not a page of the dynamic memory").get();
+}
+
+// drop a reference on the page at `addr`, and free the page with the last one
+pub fn put_page(addr: usize) {
+    if frame(addr).expect("not a page of the dynamic memory").put() == 0 {
+        deallocate(addr, PAGE_SIZE as usize);
+    }
+}
+
+// the buddy allocator serves `len` with a block of 2^n pages
+pub fn block_len(len: usize) -> usize {
+    let page = PAGE_SIZE as usize;
+    if len == 0 {
+        return 0;
+    }
+    len.div_ceil(page).next_power_of_two() * page
+}
+
+// update the frames of an allocation of `len` bytes, under the lock of the allocator
+fn mark_allocated(addr: Option<usize>, len: usize) -> Option<usize> {
+    if let (Some(addr), Some(db)) = (addr, FRAMES.get()) {
+        db.mark_allocated(addr, len, FrameFlags::KERNEL);
+    }
+    addr
+}
+
+fn mark_freed(addr: usize, len: usize) {
+    if let Some(db) = FRAMES.get() {
+        db.mark_freed(addr, len);
+    }
 }
 
 pub fn allocator_info() -> AllocatorInfo {
     let data = MEMORY_DATA.lock();
+    let ranges = data.zones.ranges().map(|x| RangeInfo {
+        zone: x.zone(),
+        phys_addr: x.phys_addr(),
+        buddy: *x.info(),
+        used: x.used(),
+    });
     AllocatorInfo {
-        buddy: *data.buddyblock.info(),
-        used: data.buddyblock.used(),
+        ranges: ranges.collect(),
+        used: data.zones.used(None),
     }
 }
 
 pub fn allocator_size_info() -> AllocatorSizeInfo {
     let data = MEMORY_DATA.lock();
     AllocatorSizeInfo {
-        len: data.buddyblock.info().data_len(),
-        used: data.buddyblock.used(),
+        len: data.zones.data_len(None),
+        used: data.zones.used(None),
     }
 }
 
-pub fn alloc_zero(len: usize) -> Option<usize> {
+pub fn zone_size_info(zone: Zone) -> AllocatorSizeInfo {
+    let data = MEMORY_DATA.lock();
+    AllocatorSizeInfo {
+        len: data.zones.data_len(Some(zone)),
+        used: data.zones.used(Some(zone)),
+    }
+}
+
+// `len` is rounded up to PAGE_SIZE, and the address is aligned in PAGE_SIZE
+pub fn allocate(len: usize) -> Option<usize> {
+    allocate_in_zone(len, Zone::Normal)
+}
+
+// physical memory of `zone` or a lower one, e.g. Zone::Dma for ISA DMA buffers. see `allocate`.
+pub fn allocate_in_zone(len: usize, zone: Zone) -> Option<usize> {
+    let mut data = MEMORY_DATA.lock();
+    mark_allocated(data.zones.alloc(len, zone), block_len(len))
+}
+
+// 2^order pages, aligned in PAGE_SIZE but not necessarily in their size
+pub fn allocate_pages(order: u32) -> Option<usize> {
+    let mut data = MEMORY_DATA.lock();
+    mark_allocated(data.zones.alloc_pages(order, Zone::Normal), (PAGE_SIZE as usize) << order)
+}
+
+pub fn deallocate_pages(addr: usize, order: u32) {
+    deallocate(addr, (PAGE_SIZE as usize) << order);
+}
+
+// `len` rounded up to PAGE_SIZE, whose physical address is aligned in `align`, a power of two.
+// it must be freed by `deallocate_aligned`.
+pub fn allocate_aligned(len: usize, align: usize) -> Option<usize> {
+    let mut data = MEMORY_DATA.lock();
+    mark_allocated(data.zones.alloc_aligned(len, align, Zone::Normal), len)
+}
+
+// like `allocate_aligned`, but the virtual address is aligned instead, e.g. for the heap. the
+// dynamic memory is not mapped at a fixed offset, so both are not aligned together.
+pub fn allocate_aligned_virt(len: usize, align: usize) -> Option<usize> {
     let mut data = MEMORY_DATA.lock();
-    data.buddyblock.alloc(len).map(|addr| {
+    mark_allocated(data.zones.alloc_aligned_virt(len, align, Zone::Normal), len)
+}
+
+pub fn deallocate_aligned(addr: usize, len: usize) {
+    let mut data = MEMORY_DATA.lock();
+    mark_freed(addr, len);
+    data.zones.add_free_range(addr, len);
+}
+
+pub fn alloc_zero(len: usize) -> Option<usize> {
+    allocate(len).map(|addr| {
         unsafe {
             core::ptr::write_bytes(addr as *mut u8, 0, len);
         }
@@ -370,7 +543,65 @@ pub fn alloc_zero(len: usize) -> Option<usize> {
 
 pub fn deallocate(addr: usize, len: usize) {
     let mut data = MEMORY_DATA.lock();
-    data.buddyblock.dealloc(addr, len);
+    data.zones.dealloc(addr, len);
+    mark_freed(addr, block_len(len));
+}
+
+// like `deallocate`, but a bad free is returned rather than panicking
+pub fn try_deallocate(addr: usize, len: usize) -> Result<(), DeallocError> {
+    let mut data = MEMORY_DATA.lock();
+    data.zones.try_dealloc(addr, len)?;
+    mark_freed(addr, block_len(len));
+    Ok(())
+}
+
+// pages of a slab allocator taken from the buddy allocator, e.g. `SlabAllocator<T, BuddyPages>`
+pub struct BuddyPages;
+
+unsafe impl PageAllocator for BuddyPages {
+    const MAX_ORDER: u32 = slab_alloc::MAX_SLAB_ORDER;
+
+    fn allocate(&mut self) -> Option<NonNull<[u8; slab_alloc::PAGE_SIZE]>> {
+        self.allocate_order(0).map(NonNull::cast)
+    }
+
+    unsafe fn deallocate(&mut self, ptr: NonNull<[u8; slab_alloc::PAGE_SIZE]>) {
+        unsafe { self.deallocate_order(ptr.cast(), 0); }
+    }
+
+    fn allocate_order(&mut self, order: u32) -> Option<NonNull<u8>> {
+        let addr = allocate_pages(order)?;
+        set_frame_flags(addr, slab_alloc::PAGE_SIZE << order, FrameFlags::SLAB);
+        NonNull::new(addr as *mut u8)
+    }
+
+    unsafe fn deallocate_order(&mut self, ptr: NonNull<u8>, order: u32) {
+        deallocate_pages(ptr.as_ptr() as usize, order);
+    }
+}
+
+// mark `addr` not present so that any access faults, or map it back. the physical page
+// stays in the entry, so the page is restored as it was.
+// Safety: `addr` must be a page of the dynamic memory owned by the caller
+pub unsafe fn set_guard_page(addr: VirtAddr, guard: bool) {
+    let entry = find_page_entry(addr).expect("guard page is not mapped");
+    let mut flags = entry.flags();
+    flags.set(PageTableFlags::PRESENT, !guard);
+    entry.set_flags(flags);
+    tlb::flush(addr);
+}
+
+// page table entry of the 4KiB page containing `virt`, if its tables are present
+fn find_page_entry(virt: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let mut table = get_table_mut();
+    for idx in [virt.p4_index(), virt.p3_index(), virt.p2_index()] {
+        let flags = table[idx].flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = unsafe { &mut *phys_to_virt(table[idx].addr()).as_mut_ptr() };
+    }
+    Some(&mut table[virt.p1_index()])
 }
 
 pub fn print_e820_map() {
@@ -446,7 +677,12 @@ fn print_table_r(table: &PageTable, names: &[&str], depth: usize, virt: u64) {
     }
 }
 
-fn get_table() -> &'static PageTable {
+// physical address of the kernel PML4, loaded in CR3 for kernel tasks
+pub fn kernel_page_table() -> PhysAddr {
+    virt_to_phys(VirtAddr::new(PAGE_TABLE_ADDR))
+}
+
+pub(crate) fn get_table() -> &'static PageTable {
     get_table_mut()
 }
 
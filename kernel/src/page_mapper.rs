@@ -0,0 +1,312 @@
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+use lazy_static::lazy_static;
+
+use crate::address_space::no_execute_enabled;
+use crate::frame::FrameFlags;
+use crate::irq_mutex::IrqMutex;
+use crate::memory::{PAGE_SIZE, alloc_zero, kernel_page_table, phys_to_virt, set_frame_flags, virt_to_phys};
+
+pub const HUGE_PAGE_SIZE: u64 = 0x0020_0000;
+
+// the kernel area reserved for I/O mapping, up to the end of PML4[256] so that the tables
+// created for it are shared by every address space
+const IO_MAP_START: u64 = 0xffff_8000_2000_0000;
+const IO_MAP_END: u64 = 0xffff_8080_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    OutOfMemory,
+    AlreadyMapped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    // 4 KiB, in a page table
+    Small,
+    // 2 MiB, in a page directory
+    Huge,
+}
+
+// how a `PageMapper` reaches the page tables and gets new ones.
+// Safety: `table` must return a valid pointer to the table at `phys`, and `alloc_table` a
+// zeroed page nobody else uses.
+pub unsafe trait PageTables {
+    fn table(&self, phys: PhysAddr) -> *mut PageTable;
+    fn alloc_table(&mut self) -> Option<PhysAddr>;
+    // drop the TLB entry of `virt` after it is unmapped
+    fn flush(&self, virt: VirtAddr);
+}
+
+// tables in the dynamic memory or the kernel area, for the running cpu
+pub struct KernelTables;
+
+// walks a PML4 and the tables below it, creating the missing ones when mapping
+pub struct PageMapper<T: PageTables = KernelTables> {
+    pml4: *mut PageTable,
+    tables: T,
+}
+
+unsafe impl<T: PageTables + Send> Send for PageMapper<T> {}
+
+lazy_static! {
+    static ref KERNEL_MAPPER: IrqMutex<PageMapper> = IrqMutex::new(unsafe {
+        PageMapper::new(phys_to_virt(kernel_page_table()).as_mut_ptr(), KernelTables)
+    });
+}
+
+// next free address of the I/O mapping area
+static IO_MAP_NEXT: IrqMutex<u64> = IrqMutex::new(IO_MAP_START);
+
+impl PageSize {
+    pub const fn bytes(self) -> u64 {
+        match self {
+            PageSize::Small => PAGE_SIZE,
+            PageSize::Huge => HUGE_PAGE_SIZE,
+        }
+    }
+}
+
+unsafe impl PageTables for KernelTables {
+    fn table(&self, phys: PhysAddr) -> *mut PageTable {
+        phys_to_virt(phys).as_mut_ptr()
+    }
+
+    fn alloc_table(&mut self) -> Option<PhysAddr> {
+        let table = alloc_zero(PAGE_SIZE as usize)?;
+        set_frame_flags(table, PAGE_SIZE as usize, FrameFlags::PAGE_TABLE);
+        Some(virt_to_phys(VirtAddr::new(table as u64)))
+    }
+
+    fn flush(&self, virt: VirtAddr) {
+        tlb::flush(virt);
+    }
+}
+
+impl<T: PageTables> PageMapper<T> {
+    // Safety: `pml4` and the tables below it must be reachable through `tables`, and nobody
+    // else may change them while the mapper is used
+    pub unsafe fn new(pml4: *mut PageTable, tables: T) -> Self {
+        Self { pml4, tables }
+    }
+
+    // map the page of `size` at `virt` to `phys`, both aligned in the size. the tables created
+    // on the way are writable, and accessible from ring 3 if `flags` is. NO_EXECUTE is ignored
+    // if the cpu does not support it.
+    pub fn map(&mut self, virt: VirtAddr, phys: PhysAddr, size: PageSize, mut flags: PageTableFlags) -> Result<(), MapError> {
+        assert!(virt.is_aligned(size.bytes()) && phys.is_aligned(size.bytes()), "unaligned mapping of {:#x} to {:#x}", virt.as_u64(), phys.as_u64());
+
+        if !no_execute_enabled() {
+            flags.remove(PageTableFlags::NO_EXECUTE);
+        }
+        flags |= PageTableFlags::PRESENT;
+        if size == PageSize::Huge {
+            flags |= PageTableFlags::HUGE_PAGE;
+        }
+
+        let entry = self.create_entry(virt, size, flags & PageTableFlags::USER_ACCESSIBLE)?;
+        if !entry.is_unused() {
+            return Err(MapError::AlreadyMapped);
+        }
+        entry.set_addr(phys, flags);
+        Ok(())
+    }
+
+    // remove the page mapped at `virt`, its first address, and returns its physical address and
+    // size. the tables are kept, even if empty.
+    pub fn unmap(&mut self, virt: VirtAddr) -> Option<(PhysAddr, PageSize)> {
+        let (entry, size) = self.find_entry(virt)?;
+        assert!(virt.is_aligned(size.bytes()), "unmapping {:#x} in the middle of a page", virt.as_u64());
+
+        let entry = unsafe { &mut *entry };
+        let phys = entry.addr();
+        entry.set_unused();
+        self.tables.flush(virt);
+        Some((phys, size))
+    }
+
+    pub fn translate(&self, virt: VirtAddr) -> Option<PhysAddr> {
+        let (entry, size) = self.find_entry(virt)?;
+        let phys = unsafe { (*entry).addr() };
+        Some(phys + (virt.as_u64() & (size.bytes() - 1)))
+    }
+
+    // entry of the page of `size` containing `virt`, creating the tables above it
+    fn create_entry(&mut self, virt: VirtAddr, size: PageSize, user: PageTableFlags) -> Result<&mut PageTableEntry, MapError> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | user;
+        let indices = [virt.p4_index(), virt.p3_index(), virt.p2_index(), virt.p1_index()];
+        let depth = if size == PageSize::Huge { 2 } else { 3 };
+
+        let mut table = unsafe { &mut *self.pml4 };
+        for idx in &indices[..depth] {
+            let entry = &mut table[*idx];
+            if entry.is_unused() {
+                let next = self.tables.alloc_table().ok_or(MapError::OutOfMemory)?;
+                entry.set_addr(next, flags);
+            }
+            else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Err(MapError::AlreadyMapped);
+            }
+            table = unsafe { &mut *self.tables.table(entry.addr()) };
+        }
+        Ok(&mut table[indices[depth]])
+    }
+
+    // present entry of the page containing `virt`. 1 GiB pages are not used.
+    fn find_entry(&self, virt: VirtAddr) -> Option<(*mut PageTableEntry, PageSize)> {
+        let indices = [virt.p4_index(), virt.p3_index(), virt.p2_index(), virt.p1_index()];
+
+        let mut table = self.pml4;
+        for (level, idx) in indices.into_iter().enumerate() {
+            let table_ref = unsafe { &mut *table };
+            let entry: *mut PageTableEntry = &mut table_ref[idx];
+            let flags = unsafe { (*entry).flags() };
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+
+            let huge = flags.contains(PageTableFlags::HUGE_PAGE);
+            match level {
+                3 => return Some((entry, PageSize::Small)),
+                2 if huge => return Some((entry, PageSize::Huge)),
+                _ if huge => return None,
+                _ => {}
+            }
+            table = self.tables.table(unsafe { (*entry).addr() });
+        }
+        None
+    }
+}
+
+// run `f` on the mapper of the kernel page table. mappings of the kernel area are shared with
+// every address space.
+pub fn with_kernel_mapper<R>(f: impl FnOnce(&mut PageMapper) -> R) -> R {
+    f(&mut KERNEL_MAPPER.lock())
+}
+
+// map `len` bytes of device memory at `phys` in the I/O mapping area, uncached, and returns the
+// address of `phys`. the mapping is never removed.
+pub fn map_io(phys: PhysAddr, len: u64) -> Result<VirtAddr, MapError> {
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_EXECUTE;
+    let start = phys.align_down(PAGE_SIZE);
+    let end = (phys + len).align_up(PAGE_SIZE);
+
+    let mut next = IO_MAP_NEXT.lock();
+    // keep the area aligned as the memory, so that 2 MiB pages can be used
+    let base = VirtAddr::new(*next).align_up(HUGE_PAGE_SIZE) + (start.as_u64() & (HUGE_PAGE_SIZE - 1));
+    assert!(base.as_u64() + (end - start) <= IO_MAP_END, "I/O mapping area is full");
+
+    with_kernel_mapper(|mapper| {
+        let mut offset = 0;
+        while start + offset < end {
+            let size = if (start + offset).is_aligned(HUGE_PAGE_SIZE) && end - (start + offset) >= HUGE_PAGE_SIZE {
+                PageSize::Huge
+            } else {
+                PageSize::Small
+            };
+            if let Err(err) = mapper.map(base + offset, start + offset, size, flags) {
+                // the area is not advanced, so remove what was mapped for the next caller
+                let mut undo = 0;
+                while undo < offset {
+                    let (_, size) = mapper.unmap(base + undo).expect("I/O page was mapped");
+                    undo += size.bytes();
+                }
+                return Err(err);
+            }
+            offset += size.bytes();
+        }
+        Ok(())
+    })?;
+
+    *next = base.as_u64() + (end - start);
+    Ok(base + (phys - start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    // tables on the host heap, whose physical address is their address
+    struct HostTables(Vec<Box<PageTable>>);
+
+    unsafe impl PageTables for HostTables {
+        fn table(&self, phys: PhysAddr) -> *mut PageTable {
+            phys.as_u64() as *mut PageTable
+        }
+
+        fn alloc_table(&mut self) -> Option<PhysAddr> {
+            let mut table = Box::new(PageTable::new());
+            let phys = PhysAddr::new(&mut *table as *mut PageTable as u64);
+            self.0.push(table);
+            Some(phys)
+        }
+
+        fn flush(&self, _virt: VirtAddr) {}
+    }
+
+    fn create_mapper() -> PageMapper<HostTables> {
+        let mut tables = HostTables(Vec::new());
+        let pml4 = tables.alloc_table().unwrap();
+        unsafe { PageMapper::new(pml4.as_u64() as *mut PageTable, tables) }
+    }
+
+    #[test]
+    fn test_map_translate_unmap() {
+        let mut mapper = create_mapper();
+        let flags = PageTableFlags::WRITABLE;
+        let virt = VirtAddr::new(0xffff_8000_2000_3000);
+
+        assert_eq!(mapper.translate(virt), None);
+        mapper.map(virt, PhysAddr::new(0x1234_5000), PageSize::Small, flags).unwrap();
+        assert_eq!(mapper.tables.0.len(), 4);
+        assert_eq!(mapper.translate(virt + 0x123u64), Some(PhysAddr::new(0x1234_5123)));
+        assert_eq!(mapper.translate(virt + PAGE_SIZE), None);
+        assert_eq!(mapper.map(virt, PhysAddr::new(0x6000), PageSize::Small, flags), Err(MapError::AlreadyMapped));
+
+        // the next page shares the tables
+        mapper.map(virt + PAGE_SIZE, PhysAddr::new(0x6000), PageSize::Small, flags).unwrap();
+        assert_eq!(mapper.tables.0.len(), 4);
+
+        assert_eq!(mapper.unmap(virt), Some((PhysAddr::new(0x1234_5000), PageSize::Small)));
+        assert_eq!(mapper.translate(virt), None);
+        assert_eq!(mapper.unmap(virt), None);
+        assert_eq!(mapper.translate(virt + PAGE_SIZE), Some(PhysAddr::new(0x6000)));
+    }
+
+    #[test]
+    fn test_map_huge_pages() {
+        let mut mapper = create_mapper();
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let virt = VirtAddr::new(0x0000_0100_0040_0000);
+
+        mapper.map(virt, PhysAddr::new(0x4000_0000), PageSize::Huge, flags).unwrap();
+        assert_eq!(mapper.tables.0.len(), 3);
+        assert_eq!(mapper.translate(virt + 0x1f_f123u64), Some(PhysAddr::new(0x401f_f123)));
+
+        // a small page cannot go inside the huge one, nor a huge one over small ones
+        assert_eq!(mapper.map(virt + PAGE_SIZE, PhysAddr::new(0x6000), PageSize::Small, flags), Err(MapError::AlreadyMapped));
+        mapper.map(virt + HUGE_PAGE_SIZE, PhysAddr::new(0x6000), PageSize::Small, flags).unwrap();
+        assert_eq!(mapper.map(virt + HUGE_PAGE_SIZE, PhysAddr::new(0x4020_0000), PageSize::Huge, flags), Err(MapError::AlreadyMapped));
+
+        // the tables are accessible from ring 3 as the pages
+        let pml4 = unsafe { &*mapper.pml4 };
+        assert!(pml4[virt.p4_index()].flags().contains(PageTableFlags::USER_ACCESSIBLE));
+
+        assert_eq!(mapper.unmap(virt), Some((PhysAddr::new(0x4000_0000), PageSize::Huge)));
+        assert_eq!(mapper.translate(virt), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "in the middle of a page")]
+    fn test_unmap_inside_huge_page() {
+        let mut mapper = create_mapper();
+        let virt = VirtAddr::new(0x0000_0100_0040_0000);
+
+        mapper.map(virt, PhysAddr::new(0x4000_0000), PageSize::Huge, PageTableFlags::WRITABLE).unwrap();
+        mapper.unmap(virt + PAGE_SIZE);
+    }
+}
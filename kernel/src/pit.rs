@@ -2,8 +2,8 @@ use core::sync::atomic::{AtomicU64, Ordering};
 use x86_64::instructions::port::Port;
 use x86_64::structures::idt::InterruptStackFrame;
 
+use crate::task;
 use crate::pic::{Irq, send_eoi};
-use crate::interrupt_queue::{InterruptMessage, intmsg_push};
 
 const TIMER_FREQ: u32 = 1000;
 const PIT_FREQ: u32 = 1193180;
@@ -35,14 +35,16 @@ pub fn tick() -> u64 {
     return TICK_COUNTER.load(Ordering::SeqCst);
 }
 
-pub fn timer_handler() {
-    TICK_COUNTER.fetch_add(1, Ordering::SeqCst);
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * TIMER_FREQ as u64).div_ceil(1000)
 }
 
 pub extern "x86-interrupt" fn timer_int_handler(_stack_frame: InterruptStackFrame) {
-    intmsg_push(InterruptMessage::Timer());
+    let tick = TICK_COUNTER.fetch_add(1, Ordering::SeqCst) + 1;
 
     unsafe {
         send_eoi(Irq::TIMER);
     }
+
+    task::timer_tick(tick);
 }
@@ -0,0 +1,25 @@
+// user programs shipped in the kernel image, see programs.S
+
+pub struct Program {
+    pub name: &'static str,
+    pub image: &'static [u8],
+}
+
+pub const PROGRAMS: [&str; 1] = ["hello"];
+
+unsafe extern "C" {
+    static _program_hello_start: u8;
+    static _program_hello_end: u8;
+}
+
+pub fn find_program(name: &str) -> Option<Program> {
+    let (name, start, end) = match name {
+        "hello" => ("hello", &raw const _program_hello_start, &raw const _program_hello_end),
+        _ => return None,
+    };
+
+    let image = unsafe {
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    Some(Program { name, image })
+}
@@ -0,0 +1,301 @@
+use crate::ring_buffer::RingBuffer;
+use crate::task::MAX_TASKS;
+
+// 0 is the highest priority
+pub const PRIORITY_LEVELS: u8 = 4;
+pub const DEFAULT_PRIORITY: u8 = 1;
+
+const RR_TIME_SLICE: u64 = 10;
+
+// time slice of each MLFQ level; lower levels run longer but less often
+const MLFQ_TIME_SLICE: [u64; PRIORITY_LEVELS as usize] = [5, 10, 20, 40];
+// every task goes back to its base priority periodically, so that demoted tasks do not starve
+const MLFQ_BOOST_INTERVAL: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyKind {
+    RoundRobin,
+    Mlfq,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnqueueReason {
+    // newly spawned, or moved from another policy
+    New,
+    // used up its time slice
+    SliceExpired,
+    // gave up the cpu before its time slice ends
+    Yielded,
+    // woken up after blocking
+    Woken,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SchedInfo {
+    pub base_priority: u8,
+    pub priority: u8,
+    // accumulated running time in ticks
+    pub runtime: u64,
+}
+
+pub trait SchedPolicy {
+    fn kind(&self) -> PolicyKind;
+    fn enqueue(&mut self, slot: usize, info: &mut SchedInfo, reason: EnqueueReason);
+    // take a ready task out of its queue. returns false if it was not queued.
+    fn remove(&mut self, slot: usize) -> bool;
+    fn pick_next(&mut self) -> Option<usize>;
+    fn has_ready(&self) -> bool;
+    fn time_slice(&self, info: &SchedInfo) -> u64;
+    // whether a ready task should preempt the running one before its time slice ends
+    fn should_preempt(&self, current: &SchedInfo) -> bool;
+    fn on_tick(&mut self, tick: u64, infos: &mut [SchedInfo; MAX_TASKS]);
+}
+
+pub struct RoundRobin {
+    queue: RingBuffer<usize, MAX_TASKS>,
+}
+
+pub struct Mlfq {
+    queues: [RingBuffer<usize, MAX_TASKS>; PRIORITY_LEVELS as usize],
+    last_boost: u64,
+}
+
+impl SchedInfo {
+    pub const fn new(priority: u8) -> Self {
+        Self {
+            base_priority: priority,
+            priority,
+            runtime: 0,
+        }
+    }
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        Self {
+            queue: RingBuffer::new_with(0),
+        }
+    }
+}
+
+impl Default for RoundRobin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SchedPolicy for RoundRobin {
+    fn kind(&self) -> PolicyKind {
+        PolicyKind::RoundRobin
+    }
+
+    fn enqueue(&mut self, slot: usize, _info: &mut SchedInfo, _reason: EnqueueReason) {
+        self.queue.push(slot);
+    }
+
+    fn remove(&mut self, slot: usize) -> bool {
+        remove_slot(&mut self.queue, slot)
+    }
+
+    fn pick_next(&mut self) -> Option<usize> {
+        self.queue.try_pop()
+    }
+
+    fn has_ready(&self) -> bool {
+        self.queue.len() > 0
+    }
+
+    fn time_slice(&self, _info: &SchedInfo) -> u64 {
+        RR_TIME_SLICE
+    }
+
+    fn should_preempt(&self, _current: &SchedInfo) -> bool {
+        false
+    }
+
+    fn on_tick(&mut self, _tick: u64, _infos: &mut [SchedInfo; MAX_TASKS]) {
+    }
+}
+
+impl Mlfq {
+    pub fn new() -> Self {
+        Self {
+            queues: core::array::from_fn(|_| RingBuffer::new_with(0)),
+            last_boost: 0,
+        }
+    }
+
+    fn highest_ready(&self) -> Option<u8> {
+        self.queues.iter().position(|x| x.len() > 0).map(|x| x as u8)
+    }
+}
+
+impl Default for Mlfq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SchedPolicy for Mlfq {
+    fn kind(&self) -> PolicyKind {
+        PolicyKind::Mlfq
+    }
+
+    fn enqueue(&mut self, slot: usize, info: &mut SchedInfo, reason: EnqueueReason) {
+        info.priority = match reason {
+            EnqueueReason::New => info.base_priority,
+            EnqueueReason::SliceExpired => (info.priority + 1).min(PRIORITY_LEVELS - 1),
+            EnqueueReason::Yielded => info.priority,
+            EnqueueReason::Woken => info.priority.saturating_sub(1).max(info.base_priority),
+        };
+        self.queues[info.priority as usize].push(slot);
+    }
+
+    fn remove(&mut self, slot: usize) -> bool {
+        self.queues.iter_mut().any(|x| remove_slot(x, slot))
+    }
+
+    fn pick_next(&mut self) -> Option<usize> {
+        self.queues.iter_mut().find_map(|x| x.try_pop())
+    }
+
+    fn has_ready(&self) -> bool {
+        self.highest_ready().is_some()
+    }
+
+    fn time_slice(&self, info: &SchedInfo) -> u64 {
+        MLFQ_TIME_SLICE[info.priority as usize]
+    }
+
+    fn should_preempt(&self, current: &SchedInfo) -> bool {
+        self.highest_ready().is_some_and(|x| x < current.priority)
+    }
+
+    fn on_tick(&mut self, tick: u64, infos: &mut [SchedInfo; MAX_TASKS]) {
+        if tick - self.last_boost < MLFQ_BOOST_INTERVAL {
+            return;
+        }
+        self.last_boost = tick;
+
+        for info in infos.iter_mut() {
+            info.priority = info.base_priority;
+        }
+
+        for level in 0..PRIORITY_LEVELS as usize {
+            for _ in 0..self.queues[level].len() {
+                let slot = self.queues[level].pop();
+                let base = infos[slot].base_priority as usize;
+                self.queues[base].push(slot);
+            }
+        }
+    }
+}
+
+// remove `slot` from `queue`, keeping the order of the others
+fn remove_slot(queue: &mut RingBuffer<usize, MAX_TASKS>, slot: usize) -> bool {
+    let mut found = false;
+    for _ in 0..queue.len() {
+        let x = queue.pop();
+        if x == slot {
+            found = true;
+        } else {
+            queue.push(x);
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mlfq_demotes_cpu_bound_task() {
+        let mut mlfq = Mlfq::new();
+        let mut info = SchedInfo::new(DEFAULT_PRIORITY);
+
+        mlfq.enqueue(3, &mut info, EnqueueReason::New);
+        assert_eq!(info.priority, DEFAULT_PRIORITY);
+
+        for expected in DEFAULT_PRIORITY + 1..PRIORITY_LEVELS {
+            assert_eq!(mlfq.pick_next(), Some(3));
+            mlfq.enqueue(3, &mut info, EnqueueReason::SliceExpired);
+            assert_eq!(info.priority, expected);
+        }
+
+        // stays at the lowest level
+        assert_eq!(mlfq.pick_next(), Some(3));
+        mlfq.enqueue(3, &mut info, EnqueueReason::SliceExpired);
+        assert_eq!(info.priority, PRIORITY_LEVELS - 1);
+    }
+
+    #[test]
+    fn test_mlfq_prefers_io_bound_task() {
+        let mut mlfq = Mlfq::new();
+        let mut infos = [SchedInfo::new(DEFAULT_PRIORITY); MAX_TASKS];
+
+        // task 1 is demoted by burning its time slice, task 2 blocks and wakes up
+        infos[1].priority = PRIORITY_LEVELS - 1;
+        mlfq.enqueue(1, &mut infos[1], EnqueueReason::SliceExpired);
+        infos[2].priority = DEFAULT_PRIORITY + 1;
+        mlfq.enqueue(2, &mut infos[2], EnqueueReason::Woken);
+
+        assert_eq!(infos[2].priority, DEFAULT_PRIORITY);
+        assert!(mlfq.should_preempt(&infos[1]));
+        assert!(mlfq.time_slice(&infos[2]) < mlfq.time_slice(&infos[1]));
+        assert_eq!(mlfq.pick_next(), Some(2));
+        assert_eq!(mlfq.pick_next(), Some(1));
+        assert_eq!(mlfq.pick_next(), None);
+    }
+
+    #[test]
+    fn test_mlfq_boost() {
+        let mut mlfq = Mlfq::new();
+        let mut infos = [SchedInfo::new(DEFAULT_PRIORITY); MAX_TASKS];
+
+        infos[5].priority = PRIORITY_LEVELS - 1;
+        mlfq.enqueue(5, &mut infos[5], EnqueueReason::Yielded);
+
+        mlfq.on_tick(MLFQ_BOOST_INTERVAL - 1, &mut infos);
+        assert_eq!(infos[5].priority, PRIORITY_LEVELS - 1);
+
+        mlfq.on_tick(MLFQ_BOOST_INTERVAL, &mut infos);
+        assert_eq!(infos[5].priority, DEFAULT_PRIORITY);
+        assert_eq!(mlfq.highest_ready(), Some(DEFAULT_PRIORITY));
+        assert_eq!(mlfq.pick_next(), Some(5));
+    }
+
+    #[test]
+    fn test_mlfq_requeue_after_priority_change() {
+        let mut mlfq = Mlfq::new();
+        let mut infos = [SchedInfo::new(DEFAULT_PRIORITY); MAX_TASKS];
+
+        mlfq.enqueue(1, &mut infos[1], EnqueueReason::New);
+        mlfq.enqueue(2, &mut infos[2], EnqueueReason::New);
+
+        assert!(mlfq.remove(2));
+        assert!(!mlfq.remove(2));
+        infos[2].base_priority = 0;
+        mlfq.enqueue(2, &mut infos[2], EnqueueReason::New);
+
+        assert_eq!(infos[2].priority, 0);
+        assert_eq!(mlfq.pick_next(), Some(2));
+        assert_eq!(mlfq.pick_next(), Some(1));
+        assert_eq!(mlfq.pick_next(), None);
+    }
+
+    #[test]
+    fn test_round_robin_order() {
+        let mut rr = RoundRobin::new();
+        let mut info = SchedInfo::new(0);
+
+        for slot in [4, 2, 7] {
+            rr.enqueue(slot, &mut info, EnqueueReason::SliceExpired);
+        }
+        assert!(!rr.should_preempt(&SchedInfo::new(PRIORITY_LEVELS - 1)));
+        assert_eq!(rr.pick_next(), Some(4));
+        assert_eq!(rr.pick_next(), Some(2));
+        assert_eq!(rr.pick_next(), Some(7));
+        assert!(!rr.has_ready());
+    }
+}
@@ -1,22 +1,47 @@
 use arrayvec::ArrayVec;
 
 use crate::{print, println};
-use crate::terminal::{ColorCode, INPUT_MAXSIZE, start_inputting};
-use crate::{pit, memory, task};
+use crate::terminal::{ColorCode, INPUT_MAXSIZE, start_inputting, wait_line};
+use crate::{pit, memory, heap, task, fpu, user, programs};
+use crate::sched_policy::{PolicyKind, PRIORITY_LEVELS};
 
 struct Command(&'static str, fn (args: &ArrayVec<&str, INPUT_MAXSIZE>), &'static str, Option<&'static str>);
 
-const COMMAND: [Command; 8] = [
+const COMMAND: [Command; 21] = [
     Command("help",         cmd_help,           "show help",            Some("help (specific command)")),
     Command("tick",         cmd_tick,           "show tick count",      None),
+    Command("sleep",        cmd_sleep,          "sleep for a while",    Some("sleep [milliseconds]")),
     Command("printpage",    cmd_print_page,     "print page table",     None),
     Command("printmmap",    cmd_print_mmap,     "print memory map",     None),
     Command("meminfo",      cmd_mem_info,       "print memory info",    None),
+    Command("slabinfo",     cmd_slab_info,      "print slab caches",    None),
+    Command("slabcheck",    cmd_slab_check,     "check every slab object for corruption", None),
+    Command("slabshrink",   cmd_slab_shrink,    "release empty pages cached by slabs", None),
+    Command("ps",           cmd_ps,             "show task list",       None),
+    Command("sched",        cmd_sched,          "show or change scheduling policy", Some("sched (rr|mlfq)")),
+    Command("setprio",      cmd_set_prio,       "change base priority of a task", Some("setprio [task id] [priority]")),
+    Command("run",          cmd_run,            "run a user program",   Some("run [program] (arguments...)")),
     Command("testtask",     cmd_test_task,      "run test task",        Some("testtask (--quit)")),
+    Command("testsched",    cmd_test_sched,     "run busy tasks to test preemption", Some("testsched (count)")),
+    Command("testoverflow", cmd_test_overflow,  "run a task overflowing its stack", None),
+    Command("testuser",     cmd_test_user,      "run a ring 3 task",    Some("testuser [spin|priv|kernel|hello]")),
+    Command("testfpu",      cmd_test_fpu,       "run tasks using sse registers", Some("testfpu (count)")),
+    Command("testheap",     cmd_test_heap,      "test the kernel heap with alloc collections", None),
     Command("testdynseq",   cmd_test_dyn_seq,   "test dynamic memory in sequencial order", None),
     Command("testdynran",   cmd_test_dyn_ran,   "test dynamic memory in random order", None),
 ];
 
+pub fn shell_main(_arg: u64) {
+    let mut buffer = [0u8; INPUT_MAXSIZE];
+
+    loop {
+        prompt();
+        if let Ok(input) = wait_line(&mut buffer) {
+            input_line(input);
+        }
+    }
+}
+
 pub fn prompt() {
     print!("> ");
     start_inputting();
@@ -61,6 +86,17 @@ fn cmd_tick(_args: &ArrayVec<&str, INPUT_MAXSIZE>) {
     println!("tick: {}", pit::tick());
 }
 
+fn cmd_sleep(args: &ArrayVec<&str, INPUT_MAXSIZE>) {
+    if let Some(ms) = args.get(1).and_then(|x| x.parse().ok()) {
+        let start = pit::tick();
+        task::sleep_ms(ms);
+        println!("slept {} ticks", pit::tick() - start);
+    }
+    else {
+        println!(color: ColorCode::ERROR, "Usage) sleep [milliseconds]");
+    }
+}
+
 fn cmd_print_page(_args: &ArrayVec<&str, INPUT_MAXSIZE>) {
     memory::print_page();
 }
@@ -73,15 +109,108 @@ fn cmd_print_mmap(_args: &ArrayVec<&str, INPUT_MAXSIZE>) {
 fn cmd_mem_info(_args: &ArrayVec<&str, INPUT_MAXSIZE>) {
     let info = memory::allocator_info();
     println!("===dynamic memory allocator infomation===");
-    println!("metadata address     : {:#018x}", info.buddy.raw_addr());
-    println!("metadata size        : {:#018x}", info.buddy.metadata_len());
-    println!("count of unit blocks : {:#018x}", info.buddy.units());
-    println!("total bitmap level   : {}", info.buddy.levels());
-    println!("=========================================");
-    println!("start address        : {:#018x}", info.buddy.data_addr());
-    println!("dynmem size          : {:#018x}", info.buddy.data_len());
+    for range in &info.ranges {
+        println!("{:?} range at phys {:#x}", range.zone, range.phys_addr);
+        println!("metadata address     : {:#018x}", range.buddy.raw_addr());
+        println!("metadata size        : {:#018x}", range.buddy.metadata_len());
+        println!("count of unit blocks : {:#018x}", range.buddy.units());
+        println!("total bitmap level   : {}", range.buddy.levels());
+        println!("start address        : {:#018x}", range.buddy.data_addr());
+        println!("dynmem size          : {:#018x}", range.buddy.data_len());
+        println!("used size            : {:#018x}", range.used);
+        println!("-----------------------------------------");
+    }
+    for zone in memory::ZONES {
+        let size = memory::zone_size_info(zone);
+        println!("zone {:<15?} : {:#x} / {:#x}", zone, size.used, size.len);
+    }
     println!("used size            : {:#018x}", info.used);
     println!("=========================================");
+    let frames = memory::frame_stats();
+    println!("frames               : {} total, {} free, {} reserved, {} shared", frames.total, frames.free, frames.reserved, frames.shared);
+    println!("frames in use        : {} kernel, {} page table, {} slab, {} user", frames.kernel, frames.page_table, frames.slab, frames.user);
+    println!("=========================================");
+    heap::print_heap_info();
+    println!("=========================================");
+}
+
+fn cmd_slab_info(_args: &ArrayVec<&str, INPUT_MAXSIZE>) {
+    println!("NAME             OBJSIZE  SLOT ORDER PER-PAGE WASTE  PAGES  FULL PARTIAL EMPTY   LIVE  QUAR    ALLOCS     FREES");
+    heap::slab_list(|name, stats| {
+        println!("{:<16} {:>7} {:>5} {:>5} {:>8} {:>5} {:>6} {:>5} {:>7} {:>5} {:>6} {:>5} {:>9} {:>9}",
+            name, stats.object_size, stats.slot_size, stats.order, stats.objects_per_page, stats.wasted_per_page,
+            stats.pages, stats.full_pages, stats.partial_pages, stats.empty_pages, stats.live, stats.quarantined, stats.allocs, stats.frees);
+    });
+}
+
+fn cmd_slab_check(_args: &ArrayVec<&str, INPUT_MAXSIZE>) {
+    heap::verify_slabs(|name, result| {
+        match result {
+            Ok(()) => println!("{:<16} ok", name),
+            Err(error) => println!(color: ColorCode::ERROR, "{:<16} {:x?}", name, error),
+        }
+    });
+}
+
+fn cmd_slab_shrink(_args: &ArrayVec<&str, INPUT_MAXSIZE>) {
+    println!("{} pages released", heap::shrink_slabs());
+}
+
+fn cmd_ps(_args: &ArrayVec<&str, INPUT_MAXSIZE>) {
+    println!("   ID NAME             STATE    PRIO  RUNTIME");
+    task::task_list(|info| {
+        let state = match info.state {
+            task::TaskState::Ready => "ready",
+            task::TaskState::Running => "running",
+            task::TaskState::Blocked => "blocked",
+            task::TaskState::Dead => "dead",
+        };
+        println!("{:>5} {:<16} {:<8} {}/{} {:>8}", info.id, info.name, state, info.priority, info.base_priority, info.runtime);
+    });
+}
+
+fn cmd_sched(args: &ArrayVec<&str, INPUT_MAXSIZE>) {
+    match args.get(1) {
+        None => {}
+        Some(&"rr") => task::set_policy(PolicyKind::RoundRobin),
+        Some(&"mlfq") => task::set_policy(PolicyKind::Mlfq),
+        Some(policy) => {
+            println!(color: ColorCode::ERROR, "'{}': unknown policy", policy);
+            return;
+        }
+    }
+    println!("scheduling policy: {:?}", task::policy());
+}
+
+fn cmd_set_prio(args: &ArrayVec<&str, INPUT_MAXSIZE>) {
+    let id = args.get(1).and_then(|x| x.parse().ok());
+    let priority = args.get(2).and_then(|x| x.parse().ok());
+
+    if let (Some(id), Some(priority)) = (id, priority) {
+        if !task::set_priority(task::TaskId::new(id), priority) {
+            println!(color: ColorCode::ERROR, "cannot set priority of task {} to {}", id, priority);
+        }
+    }
+    else {
+        println!(color: ColorCode::ERROR, "Usage) setprio [task id] [priority: 0~{}]", PRIORITY_LEVELS - 1);
+    }
+}
+
+fn cmd_run(args: &ArrayVec<&str, INPUT_MAXSIZE>) {
+    let Some(program) = args.get(1).and_then(|x| programs::find_program(x)) else {
+        println!(color: ColorCode::ERROR, "Usage) run [program] (arguments...)");
+        println!(color: ColorCode::ERROR, "programs: {:?}", programs::PROGRAMS);
+        return;
+    };
+
+    match user::spawn_elf(program.name, program.image, &args[1..]) {
+        Ok(id) => {
+            println!("task {} runs {}", id, program.name);
+            // the program owns the terminal input until it exits
+            task::wait_exit(id);
+        }
+        Err(error) => println!(color: ColorCode::ERROR, "cannot run {}: {:?}", program.name, error),
+    }
 }
 
 fn cmd_test_task(args: &ArrayVec<&str, INPUT_MAXSIZE>) {
@@ -89,23 +218,54 @@ fn cmd_test_task(args: &ArrayVec<&str, INPUT_MAXSIZE>) {
     task::test_task(quit);
 }
 
+fn cmd_test_sched(args: &ArrayVec<&str, INPUT_MAXSIZE>) {
+    let count = args.get(1).and_then(|x| x.parse().ok()).unwrap_or(2);
+    task::test_sched(count);
+}
+
+fn cmd_test_overflow(_args: &ArrayVec<&str, INPUT_MAXSIZE>) {
+    task::test_stack_overflow();
+}
+
+fn cmd_test_user(args: &ArrayVec<&str, INPUT_MAXSIZE>) {
+    if !args.get(1).is_some_and(|x| user::test_user(x)) {
+        println!(color: ColorCode::ERROR, "Usage) testuser [spin|priv|kernel|hello]");
+    }
+}
+
+fn cmd_test_fpu(args: &ArrayVec<&str, INPUT_MAXSIZE>) {
+    let count = args.get(1).and_then(|x| x.parse().ok()).unwrap_or(2);
+    fpu::test_fpu(count);
+}
+
+fn cmd_test_heap(_args: &ArrayVec<&str, INPUT_MAXSIZE>) {
+    heap::test_heap();
+}
+
 fn cmd_test_dyn_seq(_args: &ArrayVec<&str, INPUT_MAXSIZE>) {
     use core::slice::from_raw_parts_mut;
     use memory::{PAGE_SIZE, alloc_zero, deallocate, allocator_info, allocator_size_info};
 
     let info = allocator_info();
 
-    println!("memory chunk starts at {:#x}", info.buddy.data_addr());
-    println!("data range: [{:#x}, {:#x})", info.buddy.data_addr(), info.buddy.raw_addr() + info.buddy.total_len());
+    for range in &info.ranges {
+        println!("{:?} data range: [{:#x}, {:#x})", range.zone, range.buddy.data_addr(), range.buddy.raw_addr() + range.buddy.total_len());
+    }
+
+    let levels = info.ranges.iter().map(|x| x.buddy.levels()).max().unwrap_or(0);
+    for level in 0..levels {
+        // the tasks, their stacks and the heap already live in dynamic memory
+        let mut szinfo = allocator_size_info();
+        let used_before = szinfo.used;
 
-    for level in 0..info.buddy.levels() {
-        let block_count = info.buddy.units() >> level;
         let size = (PAGE_SIZE as usize) << level;
+        let block_count = (szinfo.len - used_before) / size;
+        let mut allocated = 0;
 
         println!("Bitmap Level #{} (block_count={}, size={:#x})", level, block_count, size);
 
-        let mut szinfo = allocator_size_info();
-        assert_eq!(szinfo.used, 0);
+        // the blocks are spread over the ranges, so they are chained through their first word
+        let mut chain = 0;
 
         print!("Alloc & Comp : ");
         for index in 0..block_count {
@@ -120,26 +280,37 @@ fn cmd_test_dyn_seq(_args: &ArrayVec<&str, INPUT_MAXSIZE>) {
                         println!("comparison fail: level={} size={} index={}", level, size, index);
                     }
                 }
+                slice[0] = chain as u32;
+                slice[1] = (chain >> 32) as u32;
+                chain = addr;
+                allocated += 1;
                 print!(".");
             }
             else {
-                println!("alloc() fail: level={} size={} index={}", level, size, index);
-                return;
+                // the free memory may be too fragmented for all the blocks
+                print!("\nalloc() fail: level={} size={} index={}", level, size, index);
+                break;
             }
         }
 
+        // other tasks may allocate meanwhile, so a mismatch fails the test without a panic
         szinfo = allocator_size_info();
-        assert_eq!(szinfo.used, szinfo.len / size * size);
+        if szinfo.used != used_before + allocated * size {
+            print!("\nused size fail: level={} used={:#x} expected={:#x}", level, szinfo.used, used_before + allocated * size);
+        }
 
         print!("\nDeallocation : ");
-        for index in 0..block_count {
-            let addr = info.buddy.data_addr() + size * index;
+        while chain != 0 {
+            let addr = chain;
+            chain = unsafe { core::ptr::read_volatile(addr as *const usize) };
             deallocate(addr, size);
             print!(".");
         }
 
         szinfo = allocator_size_info();
-        assert_eq!(szinfo.used, 0);
+        if szinfo.used != used_before {
+            print!("\nused size fail: level={} used={:#x} expected={:#x}", level, szinfo.used, used_before);
+        }
 
         println!();
     }
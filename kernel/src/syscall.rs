@@ -0,0 +1,168 @@
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::registers::segmentation::SegmentSelector;
+
+use crate::{print, pit, task, terminal};
+use crate::address_space::check_user_range;
+use crate::gdt::{KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_READ_LINE: u64 = 1;
+pub const SYS_SLEEP: u64 = 2;
+pub const SYS_TICK: u64 = 3;
+pub const SYS_EXIT: u64 = 4;
+pub const SYS_ALLOC_PAGES: u64 = 5;
+
+// at most this many pages by one SYS_ALLOC_PAGES
+pub const ALLOC_PAGES_MAX: u64 = 256;
+
+// returned to ring 3 as a negative value in rax
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    InvalidSyscall = -1,
+    BadAddress = -2,
+    InvalidArgument = -3,
+    OutOfMemory = -4,
+    // the line does not fit in the buffer, and is left in the input
+    BufferTooSmall = -5,
+}
+
+// registers of the calling task, pushed by `syscall_entry`
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+type SyscallResult = Result<u64, SyscallError>;
+
+const SYSCALL_TABLE: [fn(&SyscallFrame) -> SyscallResult; 6] = [
+    sys_write,
+    sys_read_line,
+    sys_sleep,
+    sys_tick,
+    sys_exit,
+    sys_alloc_pages,
+];
+
+unsafe extern "C" {
+    fn syscall_entry();
+    static mut syscall_kernel_rsp: u64;
+}
+
+// Safety: must be called once at boot, after the GDT is loaded
+pub unsafe fn init_syscall() {
+    unsafe {
+        Efer::update(|x| x.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+
+    Star::write(
+        SegmentSelector(USER_CODE_SELECTOR),
+        SegmentSelector(USER_DATA_SELECTOR),
+        SegmentSelector(KERNEL_CODE_SELECTOR),
+        SegmentSelector(KERNEL_DATA_SELECTOR),
+    ).expect("invalid segment selectors for syscall");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // the entry stub runs on the user stack until it switches, so interrupts wait until then
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+}
+
+// stack `syscall_entry` switches to, same as the ring 0 stack in the TSS
+pub fn set_kernel_stack(top: VirtAddr) {
+    let rsp = &raw mut syscall_kernel_rsp;
+    unsafe {
+        *rsp = top.as_u64();
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn syscall_handler(frame: &mut SyscallFrame) {
+    let result = match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some(syscall) => syscall(frame),
+        None => Err(SyscallError::InvalidSyscall),
+    };
+
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(error) => error as i64 as u64,
+    };
+}
+
+// the user memory [addr, addr + len), checked to be accessible from ring 3
+fn user_slice<'a>(addr: u64, len: u64) -> Result<&'a [u8], SyscallError> {
+    if !check_user_range(VirtAddr::try_new(addr).map_err(|_| SyscallError::BadAddress)?, len, false) {
+        return Err(SyscallError::BadAddress);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+
+fn user_slice_mut<'a>(addr: u64, len: u64) -> Result<&'a mut [u8], SyscallError> {
+    if !check_user_range(VirtAddr::try_new(addr).map_err(|_| SyscallError::BadAddress)?, len, true) {
+        return Err(SyscallError::BadAddress);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len as usize) })
+}
+
+// write(buf, len) -> len
+fn sys_write(frame: &SyscallFrame) -> SyscallResult {
+    let buf = user_slice(frame.rdi, frame.rsi)?;
+    let s = core::str::from_utf8(buf).map_err(|_| SyscallError::InvalidArgument)?;
+    print!("{}", s);
+    Ok(buf.len() as u64)
+}
+
+// read_line(buf, len) -> length of the line, without '\n'
+fn sys_read_line(frame: &SyscallFrame) -> SyscallResult {
+    let buf = user_slice_mut(frame.rdi, frame.rsi)?;
+    terminal::start_inputting();
+    match terminal::wait_line(buf) {
+        Ok(line) => Ok(line.len() as u64),
+        Err(_) => Err(SyscallError::BufferTooSmall),
+    }
+}
+
+// sleep(ms) -> 0
+fn sys_sleep(frame: &SyscallFrame) -> SyscallResult {
+    task::sleep_ms(frame.rdi);
+    Ok(0)
+}
+
+// tick() -> tick count
+fn sys_tick(_frame: &SyscallFrame) -> SyscallResult {
+    Ok(pit::tick())
+}
+
+// exit(code) -> never returns
+fn sys_exit(_frame: &SyscallFrame) -> SyscallResult {
+    task::exit();
+}
+
+// alloc_pages(count) -> address of zeroed pages, writable from ring 3
+fn sys_alloc_pages(frame: &SyscallFrame) -> SyscallResult {
+    let count = frame.rdi;
+    if count == 0 || count > ALLOC_PAGES_MAX {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    task::with_address_space(|space| space.alloc_pages(count))
+        .ok_or(SyscallError::InvalidSyscall)?
+        .map(|x| x.as_u64())
+        .ok_or(SyscallError::OutOfMemory)
+}
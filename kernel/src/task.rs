@@ -1,47 +1,703 @@
+use core::mem::size_of;
+use core::ptr::null_mut;
 use lazy_static::lazy_static;
-use x86_64::VirtAddr;
-use x86_64::registers::rflags;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::rflags::{self, RFlags};
+use x86_64::instructions::interrupts::{self, without_interrupts};
 
-use crate::println;
+use crate::{println, pit, fpu, gdt, syscall};
 use crate::irq_mutex::IrqMutex;
+use crate::sched_policy::{DEFAULT_PRIORITY, PRIORITY_LEVELS, EnqueueReason, Mlfq, PolicyKind, RoundRobin, SchedInfo, SchedPolicy};
 use crate::context::{Context, switch_context};
+use crate::address_space::{AddressSpace, switch_page_table};
 use crate::gdt::{KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR};
+use crate::memory::{PAGE_SIZE, alloc_zero, deallocate, kernel_page_table, set_guard_page};
+use crate::wait_queue::WaitQueue;
+
+pub const MAX_TASKS: usize = 64;
+// including the guard page at the bottom
+pub const TASK_STACK_SIZE: usize = 0x10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Ready,
+    Running,
+    Blocked,
+    Dead,
+}
 
-#[allow(dead_code)]
 pub struct Task {
+    id: TaskId,
+    slot: usize,
+    name: &'static str,
+    state: TaskState,
     context: Context,
     stack: VirtAddr,
     stack_size: usize,
+    entry: fn(u64),
+    arg: u64,
+    wake_tick: u64,
+    timer_next: *mut Task,
+    pub(crate) wait_next: *mut Task,
+    // None for kernel tasks, which run on the kernel page table
+    address_space: Option<AddressSpace>,
 }
 
-pub struct Scheduler {
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub state: TaskState,
+    pub priority: u8,
+    pub base_priority: u8,
+    pub runtime: u64,
+}
 
+pub struct Scheduler {
+    tasks: [*mut Task; MAX_TASKS],
+    infos: [SchedInfo; MAX_TASKS],
+    policy: PolicyKind,
+    round_robin: RoundRobin,
+    mlfq: Mlfq,
+    current: usize,
+    idle: usize,
+    next_id: u64,
+    slice_left: u64,
+    // sleeping tasks sorted by `wake_tick`, linked through `timer_next`
+    timers: *mut Task,
+    // task whose fpu state is loaded in the fpu registers
+    fpu_owner: *mut Task,
 }
 
+unsafe impl Send for Scheduler {}
+
 lazy_static! {
     static ref SCHEDULER: IrqMutex<Scheduler> = IrqMutex::new(Scheduler {
-
+        tasks: [null_mut(); MAX_TASKS],
+        infos: [SchedInfo::new(DEFAULT_PRIORITY); MAX_TASKS],
+        policy: PolicyKind::Mlfq,
+        round_robin: RoundRobin::new(),
+        mlfq: Mlfq::new(),
+        current: 0,
+        idle: 0,
+        next_id: 0,
+        slice_left: 0,
+        timers: null_mut(),
+        fpu_owner: null_mut(),
     });
 }
 
+// woken up whenever a task exits
+static EXIT_QUEUE: WaitQueue = WaitQueue::new();
+
+impl TaskId {
+    pub const fn new(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+impl core::fmt::Display for TaskId {
+    fn fmt(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+        core::fmt::Display::fmt(&self.0, formatter)
+    }
+}
+
 impl Task {
-    pub fn new() {
+    // Safety: `stack` must be allocated by `alloc_stack` with `stack_size` and owned by the new task,
+    // or be null with `stack_size` 0 for a task running on a stack it does not own.
+    unsafe fn new(id: TaskId, slot: usize, name: &'static str, entry: fn(u64), arg: u64, stack: VirtAddr, stack_size: usize) -> Option<*mut Task> {
+        let raw = alloc_zero(size_of::<Task>())?;
+        let task = raw as *mut Task;
+
+        let mut context = Context::new();
+        if stack_size > 0 {
+            context.rip = task_entry as *const () as u64;
+            context.cs = KERNEL_CODE_SELECTOR.into();
+            context.rflags = (RFlags::INTERRUPT_FLAG | RFlags::from_bits_retain(0x2)).bits();
+
+            // as if `task_entry` was called, so that rsp + 8 is aligned in 16 bytes
+            context.rsp = (stack + stack_size as u64).as_u64() - 8;
+            context.rbp = 0;
+
+            context.ss = KERNEL_DATA_SELECTOR.into();
+            context.ds = KERNEL_DATA_SELECTOR.into();
+            context.es = KERNEL_DATA_SELECTOR.into();
+            context.fs = KERNEL_DATA_SELECTOR.into();
+            context.gs = KERNEL_DATA_SELECTOR.into();
+
+            context.rdi = raw as u64;
+        }
+
+        unsafe {
+            core::ptr::write(task, Task {
+                id,
+                slot,
+                name,
+                state: TaskState::Ready,
+                context,
+                stack,
+                stack_size,
+                entry,
+                arg,
+                wake_tick: 0,
+                timer_next: null_mut(),
+                wait_next: null_mut(),
+                address_space: None,
+            });
+        }
+
+        Some(task)
+    }
+
+    // Safety: `task` must have been created by `Task::new` and must not be running
+    unsafe fn free(task: *mut Task) {
+        unsafe {
+            if (*task).stack_size > 0 {
+                free_stack((*task).stack, (*task).stack_size);
+            }
+            core::ptr::drop_in_place(&raw mut (*task).address_space);
+        }
+        deallocate(task as usize, size_of::<Task>());
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn state(&self) -> TaskState {
+        self.state
+    }
+
+    fn page_table(&self) -> PhysAddr {
+        match &self.address_space {
+            Some(space) => space.page_table(),
+            None => kernel_page_table(),
+        }
+    }
+}
+
+impl Scheduler {
+    fn alloc_id(&mut self) -> TaskId {
+        let id = TaskId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        self.tasks.iter().position(|x| x.is_null())
+    }
+
+    fn current_task(&self) -> &Task {
+        unsafe { &*self.tasks[self.current] }
+    }
+
+    fn policy(&mut self) -> &mut dyn SchedPolicy {
+        self.policy_with_infos().0
+    }
+
+    fn policy_with_infos(&mut self) -> (&mut dyn SchedPolicy, &mut [SchedInfo; MAX_TASKS]) {
+        let policy: &mut dyn SchedPolicy = match self.policy {
+            PolicyKind::RoundRobin => &mut self.round_robin,
+            PolicyKind::Mlfq => &mut self.mlfq,
+        };
+        (policy, &mut self.infos)
+    }
+
+    fn enqueue(&mut self, slot: usize, reason: EnqueueReason) {
+        let (policy, infos) = self.policy_with_infos();
+        policy.enqueue(slot, &mut infos[slot], reason);
+    }
+
+    fn time_slice(&mut self, slot: usize) -> u64 {
+        let (policy, infos) = self.policy_with_infos();
+        policy.time_slice(&infos[slot])
+    }
+
+    fn set_policy(&mut self, kind: PolicyKind) {
+        if self.policy == kind {
+            return;
+        }
+
+        let mut ready = [0; MAX_TASKS];
+        let mut count = 0;
+        while let Some(slot) = self.policy().pick_next() {
+            ready[count] = slot;
+            count += 1;
+        }
+
+        self.policy = kind;
+        for &slot in &ready[..count] {
+            self.enqueue(slot, EnqueueReason::New);
+        }
+    }
+
+    // pick the next task to run. returns raw contexts to switch between, or None if
+    // the current task should keep running.
+    fn switch_next(&mut self) -> Option<(*mut Context, *const Context)> {
+        let prev = self.current;
+        let prev_running = unsafe { (*self.tasks[prev]).state == TaskState::Running };
+
+        let next = match self.policy().pick_next() {
+            Some(next) => next,
+            None if prev_running => {
+                self.slice_left = self.time_slice(prev);
+                return None;
+            }
+            None => self.idle,
+        };
+
+        unsafe {
+            let prev_task = &mut *self.tasks[prev];
+            if prev_task.state == TaskState::Running {
+                prev_task.state = TaskState::Ready;
+                if prev != self.idle {
+                    let reason = if self.slice_left == 0 { EnqueueReason::SliceExpired } else { EnqueueReason::Yielded };
+                    self.enqueue(prev, reason);
+                }
+            }
+
+            let next_task = &mut *self.tasks[next];
+            next_task.state = TaskState::Running;
+            self.current = next;
+            self.slice_left = self.time_slice(next);
+
+            // other tasks trap on their first fpu instruction, see `fpu_trap`
+            fpu::set_task_switched(self.tasks[next] != self.fpu_owner);
+
+            switch_page_table(next_task.page_table());
+            if next_task.stack_size > 0 {
+                let stack_top = next_task.stack + next_task.stack_size as u64;
+                gdt::set_kernel_stack(stack_top);
+                syscall::set_kernel_stack(stack_top);
+            }
+
+            Some((&mut prev_task.context, &next_task.context))
+        }
+    }
+
+    // Safety: `task` must be a valid task which is not blocked on anything else
+    unsafe fn wake(&mut self, task: *mut Task) {
+        let task = unsafe { &mut *task };
+        if task.state == TaskState::Blocked {
+            task.state = TaskState::Ready;
+            self.enqueue(task.slot, EnqueueReason::Woken);
+        }
+    }
+
+    // Safety: `task` must be a valid task which is not in the timer list
+    unsafe fn insert_timer(&mut self, task: *mut Task, wake_tick: u64) {
+        unsafe {
+            (*task).wake_tick = wake_tick;
+
+            let mut link: *mut *mut Task = &mut self.timers;
+            while !(*link).is_null() && (**link).wake_tick <= wake_tick {
+                link = &mut (**link).timer_next;
+            }
+
+            (*task).timer_next = *link;
+            *link = task;
+        }
+    }
+
+    fn wake_expired(&mut self, tick: u64) {
+        while !self.timers.is_null() && unsafe { (*self.timers).wake_tick } <= tick {
+            let task = self.timers;
+            unsafe {
+                self.timers = (*task).timer_next;
+                (*task).timer_next = null_mut();
+                self.wake(task);
+            }
+        }
+    }
+
+    fn take_dead(&mut self) -> Option<*mut Task> {
+        for (slot, task) in self.tasks.iter_mut().enumerate() {
+            if slot != self.current && !task.is_null() && unsafe { (**task).state } == TaskState::Dead {
+                let dead = *task;
+                *task = null_mut();
+                if self.fpu_owner == dead {
+                    self.fpu_owner = null_mut();
+                }
+                return Some(dead);
+            }
+        }
+        None
+    }
+}
+
+// Safety: must be called once from kmain, on the boot stack and after the memory is initialized
+pub unsafe fn init_task() {
+    {
+        let mut sched = SCHEDULER.lock();
+        let id = sched.alloc_id();
+
+        // kmain becomes the first task, running on the boot stack
+        let task = unsafe {
+            Task::new(id, 0, "kmain", |_| {}, 0, VirtAddr::zero(), 0)
+        }.expect("cannot allocate the boot task");
+
+        unsafe {
+            (*task).state = TaskState::Running;
+        }
+        sched.tasks[0] = task;
+        // kmain dispatches interrupt messages, so it must not wait behind busy tasks
+        sched.infos[0] = SchedInfo::new(0);
+        sched.current = 0;
+    }
+
+    let idle = create_task("idle", idle_main, 0, None).expect("cannot allocate the idle task");
+    SCHEDULER.lock().idle = idle;
+}
+
+fn create_task(name: &'static str, entry: fn(u64), arg: u64, address_space: Option<AddressSpace>) -> Option<usize> {
+    let stack = alloc_stack(TASK_STACK_SIZE)?;
+
+    let mut sched = SCHEDULER.lock();
+    let slot = sched.free_slot();
+    let task = slot.and_then(|slot| {
+        let id = sched.alloc_id();
+        unsafe { Task::new(id, slot, name, entry, arg, stack, TASK_STACK_SIZE) }
+    });
+
+    match (slot, task) {
+        (Some(slot), Some(task)) => {
+            unsafe {
+                (*task).address_space = address_space;
+            }
+            sched.tasks[slot] = task;
+            sched.infos[slot] = SchedInfo::new(DEFAULT_PRIORITY);
+            Some(slot)
+        }
+        _ => {
+            drop(sched);
+            unsafe { free_stack(stack, TASK_STACK_SIZE); }
+            None
+        }
+    }
+}
+
+// allocate a stack whose lowest page is left unmapped, so that an overflow faults
+// instead of overwriting the memory below
+fn alloc_stack(size: usize) -> Option<VirtAddr> {
+    let stack = VirtAddr::new(alloc_zero(size)? as u64);
+    unsafe {
+        set_guard_page(stack, true);
+    }
+    Some(stack)
+}
+
+// Safety: `stack` must be allocated by `alloc_stack` with `size` and no longer in use
+unsafe fn free_stack(stack: VirtAddr, size: usize) {
+    unsafe {
+        set_guard_page(stack, false);
+    }
+    deallocate(stack.as_u64() as usize, size);
+}
+
+pub fn spawn(name: &'static str, entry: fn(u64), arg: u64) -> Option<TaskId> {
+    spawn_task(name, entry, arg, None)
+}
+
+// spawn a task running on `address_space` instead of the kernel page table
+pub fn spawn_with_space(name: &'static str, entry: fn(u64), arg: u64, address_space: AddressSpace) -> Option<TaskId> {
+    spawn_task(name, entry, arg, Some(address_space))
+}
+
+fn spawn_task(name: &'static str, entry: fn(u64), arg: u64, address_space: Option<AddressSpace>) -> Option<TaskId> {
+    let slot = create_task(name, entry, arg, address_space)?;
+
+    let mut sched = SCHEDULER.lock();
+    sched.enqueue(slot, EnqueueReason::New);
+    Some(unsafe { (*sched.tasks[slot]).id })
+}
+
+pub fn yield_now() {
+    schedule();
+}
+
+pub fn exit() -> ! {
+    // a dead task is not scheduled again, so it must wake the waiters before it can be preempted
+    without_interrupts(|| {
+        {
+            let sched = SCHEDULER.lock();
+            let current = sched.tasks[sched.current];
+            unsafe {
+                (*current).state = TaskState::Dead;
+            }
+        }
+        EXIT_QUEUE.wake_all();
+    });
+
+    schedule();
+    unreachable!("dead task is scheduled");
+}
+
+// block until the task `id` exits. returns at once if there is no such task.
+pub fn wait_exit(id: TaskId) {
+    EXIT_QUEUE.wait_until(|| {
+        let sched = SCHEDULER.lock();
+        !sched.tasks.iter().any(|&x| !x.is_null() && unsafe { (*x).id == id && (*x).state != TaskState::Dead })
+    });
+}
+
+pub fn current_task_id() -> TaskId {
+    SCHEDULER.lock().current_task().id
+}
+
+pub fn sleep_ms(ms: u64) {
+    sleep_ticks(pit::ms_to_ticks(ms));
+}
+
+pub fn sleep_ticks(ticks: u64) {
+    if ticks == 0 {
+        yield_now();
+        return;
+    }
+
+    without_interrupts(|| {
+        {
+            let mut sched = SCHEDULER.lock();
+            let current = sched.tasks[sched.current];
+            unsafe {
+                sched.insert_timer(current, pit::tick() + ticks);
+                (*current).state = TaskState::Blocked;
+            }
+        }
+        schedule();
+    });
+}
 
+pub(crate) fn current_task_ptr() -> *mut Task {
+    let sched = SCHEDULER.lock();
+    sched.tasks[sched.current]
+}
+
+// run `f` on the address space of the current task, or None for a kernel task.
+// only the task itself touches its address space, so no lock is needed.
+pub(crate) fn with_address_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    let task = current_task_ptr();
+    unsafe { (*task).address_space.as_mut().map(f) }
+}
+
+// block the current task until `wake_task` is called on it.
+// interrupts must be disabled so that a wakeup is not lost before blocking.
+pub(crate) fn block_current() {
+    assert!(!interrupts::are_enabled(), "block_current() with interrupts enabled");
+
+    {
+        let sched = SCHEDULER.lock();
+        let current = sched.tasks[sched.current];
+        unsafe {
+            (*current).state = TaskState::Blocked;
+        }
+    }
+
+    schedule();
+}
+
+// Safety: `task` must be a valid task blocked by `block_current`
+pub(crate) unsafe fn wake_task(task: *mut Task) {
+    unsafe {
+        SCHEDULER.lock().wake(task);
+    }
+}
+
+// called from the #NM handler when the current task touches the fpu with CR0.TS set
+pub fn fpu_trap() {
+    let mut sched = SCHEDULER.lock();
+    let current = sched.tasks[sched.current];
+
+    fpu::set_task_switched(false);
+    if sched.fpu_owner != current {
+        unsafe {
+            if !sched.fpu_owner.is_null() {
+                (*sched.fpu_owner).context.fpu.save();
+            }
+            (*current).context.fpu.restore();
+        }
+        sched.fpu_owner = current;
+    }
+}
+
+// the task whose stack guard page contains `addr`. called from fault handlers,
+// so it gives up instead of waiting if the scheduler is locked.
+pub fn stack_guard_owner(addr: u64) -> Option<(TaskId, &'static str)> {
+    let sched = SCHEDULER.try_lock()?;
+    sched.tasks.iter()
+        .filter(|x| !x.is_null())
+        .map(|&x| unsafe { &*x })
+        .find(|x| x.stack_size > 0 && (x.stack.as_u64()..x.stack.as_u64() + PAGE_SIZE).contains(&addr))
+        .map(|x| (x.id, x.name))
+}
+
+pub fn set_priority(id: TaskId, priority: u8) -> bool {
+    if priority >= PRIORITY_LEVELS {
+        return false;
+    }
+
+    let mut sched = SCHEDULER.lock();
+    let slot = sched.tasks.iter().position(|&x| !x.is_null() && unsafe { (*x).id } == id);
+    match slot {
+        Some(slot) if slot != sched.idle => {
+            let queued = sched.policy().remove(slot);
+            let info = &mut sched.infos[slot];
+            info.base_priority = priority;
+            info.priority = priority;
+            if queued {
+                // back in the queue of the new priority
+                sched.enqueue(slot, EnqueueReason::New);
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+pub fn policy() -> PolicyKind {
+    SCHEDULER.lock().policy
+}
+
+pub fn set_policy(kind: PolicyKind) {
+    SCHEDULER.lock().set_policy(kind);
+}
+
+pub fn task_list(f: impl FnMut(TaskInfo)) {
+    let sched = SCHEDULER.lock();
+    sched.tasks.iter()
+        .zip(sched.infos.iter())
+        .filter(|(x, _)| !x.is_null())
+        .map(|(&x, info)| unsafe {
+            TaskInfo {
+                id: (*x).id,
+                name: (*x).name,
+                state: (*x).state,
+                priority: info.priority,
+                base_priority: info.base_priority,
+                runtime: info.runtime,
+            }
+        })
+        .for_each(f);
+}
+
+// called from the timer interrupt handler after EOI
+pub fn timer_tick(tick: u64) {
+    let expired = {
+        let mut sched = SCHEDULER.lock();
+        sched.wake_expired(tick);
+
+        let current = sched.current;
+        let idle = current == sched.idle;
+        sched.slice_left = sched.slice_left.saturating_sub(1);
+        let slice_expired = sched.slice_left == 0;
+
+        let (policy, infos) = sched.policy_with_infos();
+        infos[current].runtime += 1;
+        policy.on_tick(tick, infos);
+
+        slice_expired || (idle && policy.has_ready()) || (!idle && policy.should_preempt(&infos[current]))
+    };
+
+    if expired {
+        schedule();
+    }
+}
+
+fn schedule() {
+    without_interrupts(|| {
+        let next = SCHEDULER.lock().switch_next();
+        if let Some((from, to)) = next {
+            unsafe {
+                switch_context(&mut *from, &*to);
+            }
+        }
+    });
+
+    reap_dead_tasks();
+}
+
+fn reap_dead_tasks() {
+    loop {
+        let dead = SCHEDULER.lock().take_dead();
+        match dead {
+            Some(task) => unsafe { Task::free(task) },
+            None => break,
+        }
+    }
+}
+
+fn idle_main(_arg: u64) {
+    loop {
+        interrupts::enable_and_hlt();
+        yield_now();
+    }
+}
+
+extern "C" fn task_entry(task: *mut Task) -> ! {
+    reap_dead_tasks();
+
+    let (entry, arg) = unsafe { ((*task).entry, (*task).arg) };
+    entry(arg);
+
+    exit();
+}
+
+pub fn test_sched(count: u64) {
+    fn worker(arg: u64) {
+        let id = current_task_id();
+        for round in 0..5 {
+            println!("task {} (arg={}) round #{}", id, arg, round);
+
+            // burn cpu without yielding; preemption must keep the shell alive
+            for _ in 0..2_000_000 {
+                core::hint::spin_loop();
+            }
+        }
+        println!("task {} exits", id);
+    }
+
+    for arg in 0..count {
+        if spawn("testsched", worker, arg).is_none() {
+            println!("cannot spawn task #{}", arg);
+        }
+    }
+}
+
+pub fn test_stack_overflow() {
+    fn recurse(depth: u64) -> u64 {
+        if depth == u64::MAX {
+            return 0;
+        }
+        let frame = core::hint::black_box([depth; 64]);
+        recurse(depth + 1) + frame[0]
+    }
+
+    fn worker(_arg: u64) {
+        recurse(0);
+    }
+
+    if spawn("overflow", worker, 0).is_none() {
+        println!("cannot spawn task");
     }
 }
 
 pub fn test_task(quit: bool) {
-    use core::mem::size_of;
     use spin::Mutex;
-    use crate::memory::{alloc_zero, deallocate};
 
     struct CtxData {
         parameter: u64,
         this: Context,
         main: Context,
-        stack: [u8; 8192],
+        stack: VirtAddr,
     }
 
+    const STACK_SIZE: usize = 0x4000;
+
     let parameter = 42;
 
     lazy_static! {
@@ -52,6 +708,9 @@ pub fn test_task(quit: bool) {
 
     if quit {
         if *ctx_ptr != 0 {
+            unsafe {
+                free_stack((*(*ctx_ptr as *mut CtxData)).stack, STACK_SIZE);
+            }
             deallocate(*ctx_ptr, size_of::<CtxData>());
             *ctx_ptr = 0;
         }
@@ -60,13 +719,14 @@ pub fn test_task(quit: bool) {
         if *ctx_ptr == 0 {
             let data_raw = alloc_zero(size_of::<CtxData>()).unwrap();
             let data = unsafe { &mut *(data_raw as *mut CtxData) };
+            data.stack = alloc_stack(STACK_SIZE).unwrap();
 
             data.this.rip = task_main as u64;
             data.this.cs = KERNEL_CODE_SELECTOR.into();
 
             data.this.rflags = rflags::read_raw();
 
-            data.this.rsp = data.stack.as_ptr_range().end as u64;
+            data.this.rsp = (data.stack + STACK_SIZE as u64).as_u64();
             data.this.rbp = data.this.rsp;
 
             data.this.ss = KERNEL_DATA_SELECTOR.into();
@@ -10,6 +10,7 @@ use x86_64::instructions::port::Port;
 use crate::irq_mutex::IrqMutex;
 use crate::ring_buffer::RingBuffer;
 use crate::serial::COM1;
+use crate::wait_queue::WaitQueue;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -143,6 +144,8 @@ lazy_static! {
     });
 }
 
+static INPUT_QUEUE: WaitQueue = WaitQueue::new();
+
 pub unsafe fn init_term() {
     let mut term = TERM.lock();
     term.redraw_status_lines();
@@ -195,6 +198,12 @@ pub fn getline(line: &mut [u8]) -> Result<&str, usize> {
     Ok(str::from_utf8(&line[0..size]).unwrap())
 }
 
+// block until a line is entered, then get it like `getline`
+pub fn wait_line(line: &mut [u8]) -> Result<&str, usize> {
+    INPUT_QUEUE.wait_until(has_input_line);
+    getline(line)
+}
+
 pub fn process_input(input: DecodedKey) {
     TERM.lock().process_input(input);
 }
@@ -354,6 +363,8 @@ impl Terminal {
         self.input_status = InputStatus::Waiting;
         self.input_begin = self.input.len();
         self.input_idx = self.input_begin;
+
+        INPUT_QUEUE.wake_all();
     }
 
     fn put_char(&mut self, ch: u8, keep_last: bool) {
@@ -0,0 +1,254 @@
+use core::arch::asm;
+use num_iter::range_step;
+use x86_64::VirtAddr;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::println;
+use crate::address_space::{AddressSpace, MapError, USER_END, USER_HEAP_START, USER_START};
+use crate::elf::{Elf, ElfError, PF_W, PF_X, PT_LOAD, ProgramHeader};
+use crate::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::memory::PAGE_SIZE;
+use crate::task::{self, TaskId};
+
+pub const USER_CODE_START: u64 = USER_START;
+
+// the page right below `USER_END` is left unmapped
+pub const USER_STACK_TOP: u64 = USER_END - PAGE_SIZE;
+pub const USER_STACK_SIZE: u64 = 0x10000;
+
+// auxiliary vector entry types of the System V ABI
+const AT_NULL: u64 = 0;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    Elf(ElfError),
+    OutOfMemory,
+    // outside of the program area, or overlapping another segment
+    BadSegment,
+    BadEntry,
+    // arguments do not fit in the top page of the stack
+    ArgumentsTooLong,
+    CannotSpawn,
+}
+
+impl From<MapError> for LoadError {
+    fn from(error: MapError) -> Self {
+        match error {
+            MapError::OutOfMemory => LoadError::OutOfMemory,
+            MapError::AlreadyMapped => LoadError::BadSegment,
+        }
+    }
+}
+
+// load a static ELF executable into a new address space and start it as a task
+pub fn spawn_elf(name: &'static str, image: &[u8], argv: &[&str]) -> Result<TaskId, LoadError> {
+    let elf = Elf::parse(image).map_err(LoadError::Elf)?;
+    let mut space = AddressSpace::new().ok_or(LoadError::OutOfMemory)?;
+
+    // linkers may emit empty segments for empty sections
+    for ph in elf.program_headers().filter(|x| x.p_type == PT_LOAD && x.mem_size > 0) {
+        load_segment(&mut space, &elf, &ph)?;
+    }
+
+    spawn_user(name, space, VirtAddr::new(elf.entry()), argv)
+}
+
+fn load_segment(space: &mut AddressSpace, elf: &Elf, ph: &ProgramHeader) -> Result<(), LoadError> {
+    // `Elf::parse` checked that these do not overflow
+    let start = ph.vaddr;
+    let end = ph.vaddr + ph.mem_size;
+    if start < USER_CODE_START || end > USER_HEAP_START {
+        return Err(LoadError::BadSegment);
+    }
+
+    let mut flags = PageTableFlags::empty();
+    if ph.flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if ph.flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let data = elf.segment_data(ph);
+    let data_end = start + data.len() as u64;
+    for page in range_step(start & !(PAGE_SIZE - 1), end, PAGE_SIZE) {
+        let kernel_page = space.map_page(VirtAddr::new(page), flags)?;
+
+        // the page is zeroed, so only the part in the file is copied
+        let copy_start = page.max(start);
+        let copy_end = (page + PAGE_SIZE).min(data_end);
+        if copy_start < copy_end {
+            let src = &data[(copy_start - start) as usize..(copy_end - start) as usize];
+            unsafe {
+                let dst = (kernel_page + (copy_start - page)).as_mut_ptr::<u8>();
+                core::ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len());
+            }
+        }
+    }
+    Ok(())
+}
+
+// map the user stack in `space` with `argv` on it, and spawn a task entering ring 3 at `entry`
+pub fn spawn_user(name: &'static str, mut space: AddressSpace, entry: VirtAddr, argv: &[&str]) -> Result<TaskId, LoadError> {
+    if !(USER_CODE_START..USER_HEAP_START).contains(&entry.as_u64()) {
+        return Err(LoadError::BadEntry);
+    }
+
+    let mut top_page = VirtAddr::zero();
+    for page in range_step(USER_STACK_TOP - USER_STACK_SIZE, USER_STACK_TOP, PAGE_SIZE) {
+        top_page = space.map_page(VirtAddr::new(page), PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)?;
+    }
+
+    let stack_pointer = write_arguments(top_page, entry, argv)?;
+    task::spawn_with_space(name, user_task_main, stack_pointer, space).ok_or(LoadError::CannotSpawn)
+}
+
+// lay out the initial stack like the System V ABI in the top page of the stack, which the kernel
+// accesses through `top_page`: argc, argv[], NULL, envp[] (empty), NULL, auxv[], then the strings.
+// returns the initial stack pointer in the user address space.
+fn write_arguments(top_page: VirtAddr, entry: VirtAddr, argv: &[&str]) -> Result<u64, LoadError> {
+    let page_base = USER_STACK_TOP - PAGE_SIZE;
+    let to_kernel = |user: u64| (top_page + (user - page_base)).as_mut_ptr::<u8>();
+
+    let strings_len: u64 = argv.iter().map(|x| x.len() as u64 + 1).sum();
+    let auxv = [AT_ENTRY, entry.as_u64(), AT_NULL, 0];
+    let words = 1 + (argv.len() as u64 + 1) + 1 + auxv.len() as u64;
+    if strings_len + words * 8 + 16 > PAGE_SIZE {
+        return Err(LoadError::ArgumentsTooLong);
+    }
+
+    let strings_start = USER_STACK_TOP - strings_len;
+    let stack_pointer = (strings_start - words * 8) & !0xf;
+
+    let mut word = stack_pointer;
+    let mut push = |value: u64| {
+        unsafe {
+            (to_kernel(word) as *mut u64).write_unaligned(value);
+        }
+        word += 8;
+    };
+
+    push(argv.len() as u64);
+    let mut string = strings_start;
+    for arg in argv {
+        push(string);
+        unsafe {
+            core::ptr::copy_nonoverlapping(arg.as_ptr(), to_kernel(string), arg.len());
+            to_kernel(string + arg.len() as u64).write(0);
+        }
+        string += arg.len() as u64 + 1;
+    }
+    push(0);
+    push(0);
+    for value in auxv {
+        push(value);
+    }
+
+    Ok(stack_pointer)
+}
+
+fn user_task_main(stack_pointer: u64) {
+    // see `write_arguments` for the layout
+    unsafe {
+        let stack = stack_pointer as *const u64;
+        let argc = *stack;
+        let mut aux = stack.add(argc as usize + 3);
+        let mut entry = 0;
+        while *aux != AT_NULL {
+            if *aux == AT_ENTRY {
+                entry = *aux.add(1);
+            }
+            aux = aux.add(2);
+        }
+
+        enter_user_mode(VirtAddr::new(entry), VirtAddr::new(stack_pointer), argc, stack_pointer + 8);
+    }
+}
+
+// enter ring 3 with rdi = `arg0`, rsi = `arg1` and the other registers cleared.
+// Safety: the address space of the current task must map `entry` and the stack below `stack_pointer`
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack_pointer: VirtAddr, arg0: u64, arg1: u64) -> ! {
+    let rflags = (RFlags::INTERRUPT_FLAG | RFlags::from_bits_retain(0x2)).bits();
+
+    unsafe {
+        asm!(
+            "mov ds, ax",
+            "mov es, ax",
+            "mov fs, ax",
+            "mov gs, ax",
+            // frame for iretq: ss, rsp, rflags, cs, rip
+            "push rax",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "mov rdi, r8",
+            "mov rsi, r9",
+            // do not leak kernel values to ring 3
+            "xor eax, eax",
+            "xor ebx, ebx",
+            "xor ecx, ecx",
+            "xor edx, edx",
+            "xor ebp, ebp",
+            "xor r8d, r8d",
+            "xor r9d, r9d",
+            "xor r10d, r10d",
+            "xor r11d, r11d",
+            "xor r12d, r12d",
+            "xor r13d, r13d",
+            "xor r14d, r14d",
+            "xor r15d, r15d",
+            "iretq",
+            in("rax") USER_DATA_SELECTOR as u64,
+            in("rcx") stack_pointer.as_u64(),
+            in("rdx") rflags,
+            in("rsi") USER_CODE_SELECTOR as u64,
+            in("rdi") entry.as_u64(),
+            in("r8") arg0,
+            in("r9") arg1,
+            options(noreturn),
+        );
+    }
+}
+
+pub fn test_user(kind: &str) -> bool {
+    // 1: pause; jmp 1b
+    const SPIN: &[u8] = &[0xf3, 0x90, 0xeb, 0xfc];
+    // cli; jmp .
+    const PRIVILEGED: &[u8] = &[0xfa, 0xeb, 0xfe];
+    // movabs rax, [0xffff800000000000]; jmp .
+    const KERNEL_READ: &[u8] = &[0x48, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0xff, 0xff, 0xeb, 0xfe];
+
+    // lea rdi, [rip + msg]; mov esi, 18; xor eax, eax (SYS_WRITE); syscall;
+    // mov eax, 4 (SYS_EXIT); syscall; msg: "hello from ring 3\n"
+    const HELLO: &[u8] = b"\x48\x8d\x3d\x10\x00\x00\x00\xbe\x12\x00\x00\x00\x31\xc0\x0f\x05\
+                           \xb8\x04\x00\x00\x00\x0f\x05hello from ring 3\n";
+
+    let code = match kind {
+        "spin" => SPIN,
+        "hello" => HELLO,
+        "priv" => PRIVILEGED,
+        "kernel" => KERNEL_READ,
+        _ => return false,
+    };
+
+    let Some(mut space) = AddressSpace::new() else {
+        println!("cannot allocate an address space");
+        return true;
+    };
+    let Ok(page) = space.map_page(VirtAddr::new(USER_CODE_START), PageTableFlags::empty()) else {
+        println!("cannot map the code page");
+        return true;
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), page.as_mut_ptr::<u8>(), code.len());
+    }
+
+    match spawn_user("user", space, VirtAddr::new(USER_CODE_START), &[kind]) {
+        Ok(id) => println!("task {} runs in ring 3", id),
+        Err(error) => println!("cannot spawn task: {:?}", error),
+    }
+    true
+}
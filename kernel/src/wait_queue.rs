@@ -0,0 +1,111 @@
+use core::ptr::null_mut;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::irq_mutex::IrqMutex;
+use crate::task::{Task, block_current, current_task_ptr, wake_task};
+
+pub struct WaitQueue {
+    list: IrqMutex<TaskList>,
+}
+
+// tasks linked through `Task::wait_next`
+struct TaskList {
+    head: *mut Task,
+    tail: *mut Task,
+}
+
+unsafe impl Send for TaskList {}
+
+impl TaskList {
+    const fn new() -> Self {
+        Self {
+            head: null_mut(),
+            tail: null_mut(),
+        }
+    }
+
+    // Safety: `task` must be a valid task which is not in any list
+    unsafe fn push_back(&mut self, task: *mut Task) {
+        unsafe {
+            (*task).wait_next = null_mut();
+            if self.tail.is_null() {
+                self.head = task;
+            } else {
+                (*self.tail).wait_next = task;
+            }
+        }
+        self.tail = task;
+    }
+
+    fn pop_front(&mut self) -> Option<*mut Task> {
+        if self.head.is_null() {
+            return None;
+        }
+
+        let task = self.head;
+        unsafe {
+            self.head = (*task).wait_next;
+            (*task).wait_next = null_mut();
+        }
+        if self.head.is_null() {
+            self.tail = null_mut();
+        }
+        Some(task)
+    }
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            list: IrqMutex::new(TaskList::new()),
+        }
+    }
+
+    // block the current task until it is woken up
+    pub fn wait(&self) {
+        without_interrupts(|| {
+            unsafe {
+                self.list.lock().push_back(current_task_ptr());
+            }
+            block_current();
+        });
+    }
+
+    // block the current task until `cond` holds. `cond` is checked with interrupts disabled,
+    // so a wakeup from an interrupt handler between the check and blocking is not lost.
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut cond: F) {
+        without_interrupts(|| {
+            while !cond() {
+                unsafe {
+                    self.list.lock().push_back(current_task_ptr());
+                }
+                block_current();
+            }
+        });
+    }
+
+    pub fn wake_one(&self) -> bool {
+        let task = self.list.lock().pop_front();
+        match task {
+            Some(task) => {
+                unsafe { wake_task(task); }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn wake_all(&self) -> usize {
+        let mut count = 0;
+        while self.wake_one() {
+            count += 1;
+        }
+        count
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
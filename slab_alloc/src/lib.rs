@@ -6,29 +6,166 @@ use core::mem::{align_of, size_of};
 use core::ptr::{NonNull, null_mut, write_bytes};
 use core::slice::from_raw_parts;
 
+mod magazine;
+mod size_class;
+
+pub use magazine::{MAGAZINE_BATCH, MAGAZINE_SIZE, Magazine, MagazineAllocator, PerCpu};
+pub use size_class::{SIZE_CLASSES, SIZE_CLASS_ALIGN, SIZE_CLASS_NAMES, SizeClassAllocator};
+
 pub const PAGE_SIZE: usize = 4096;
-pub const REDZONE_SIZE: u16 = 16;
+
+// a slab page spans up to 2^MAX_SLAB_ORDER pages, so that offsets in it fit in u16
+pub const MAX_SLAB_ORDER: u32 = 4;
+
+// debug modes selected by the cargo features of the same names
+pub const REDZONE: bool = cfg!(feature = "redzone");
+pub const POISON: bool = cfg!(feature = "poison");
+pub const QUARANTINE: bool = cfg!(feature = "quarantine");
+
+pub const REDZONE_SIZE: u16 = if REDZONE { 16 } else { 0 };
+
+// freed objects wait in a quarantine of this many objects per slab before they are reused
+#[cfg(feature = "quarantine")]
+pub const QUARANTINE_SIZE: usize = 16;
+
+// first objects of the pages of a slab are shifted by multiples of this, see `RawSlab::next_color`
+pub const CACHE_LINE_SIZE: usize = 64;
 
 const EMPTY_MAGIC: u16 = 0x3a49;
 const OBJECT_MAGIC: u16 = 0x6b5c;
+const QUARANTINE_MAGIC: u16 = 0x5d2e;
 const REDZONE_FILL: u8 = 0xf1;
 const UNUSED_FILL: u8 = 0xf2;
 
 pub unsafe trait PageAllocator {
+    // highest order served by `allocate_order`. allocators raising it must implement
+    // `allocate_order` and `deallocate_order`.
+    const MAX_ORDER: u32 = 0;
+
     // return value must be aligned in PAGE_SIZE
     fn allocate(&mut self) -> Option<NonNull<[u8; PAGE_SIZE]>>;
     // Safety: ptr is an address of an allocated page
     unsafe fn deallocate(&mut self, ptr: NonNull<[u8; PAGE_SIZE]>);
+
+    // 2^order contiguous pages. return value must be aligned in PAGE_SIZE, but not necessarily
+    // in its size.
+    fn allocate_order(&mut self, order: u32) -> Option<NonNull<u8>> {
+        assert!(order == 0, "page allocator serves single pages only");
+        self.allocate().map(NonNull::cast)
+    }
+
+    /// # Safety
+    /// `ptr` must be a block allocated by `allocate_order` of this allocator with the same
+    /// `order`, and nothing may use it after
+    unsafe fn deallocate_order(&mut self, ptr: NonNull<u8>, order: u32) {
+        assert!(order == 0, "page allocator serves single pages only");
+        unsafe { self.deallocate(ptr.cast()); }
+    }
 }
 
 pub struct SlabAllocator<T, PA: PageAllocator> {
-    partial_list: PageList,
+    slab: RawSlab,
     page_allocator: PA,
     _phantom: PhantomData<T>,
 }
 
 unsafe impl<T, PA: PageAllocator> Send for SlabAllocator<T, PA> {}
 
+// untyped slab of objects sized and aligned as `layout`. pages come from the page allocator
+// passed to each call, so that several slabs can share one.
+struct RawSlab {
+    name: &'static str,
+    layout: SlotLayout,
+    // run on every slot of a new page, and before a page is released. free objects stay
+    // constructed, so they are not poisoned.
+    ctor: Option<fn(NonNull<u8>)>,
+    dtor: Option<fn(NonNull<u8>)>,
+    // color of the next new page: its first object is shifted by `next_color` cache lines,
+    // up to the tail of the page
+    next_color: usize,
+    partial_list: PageList,
+    full_list: PageList,
+    // pages without objects kept for the next allocations, at most `empty_limit`
+    empty_list: PageList,
+    empty_limit: usize,
+    page_count: usize,
+    full_count: usize,
+    empty_count: usize,
+    allocs: usize,
+    frees: usize,
+    failures: usize,
+    #[cfg(feature = "quarantine")]
+    quarantine: Quarantine,
+}
+
+// FIFO of freed objects not yet reused
+#[cfg(feature = "quarantine")]
+struct Quarantine {
+    slots: [*mut SlotObject; QUARANTINE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+// a page of a slab is 2^order contiguous pages of the page allocator, and the counters of pages
+// count those
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    pub object_size: usize,
+    pub slot_size: usize,
+    pub order: u32,
+    pub objects_per_page: usize,
+    pub pages: usize,
+    pub full_pages: usize,
+    pub partial_pages: usize,
+    pub empty_pages: usize,
+    pub live: usize,
+    // freed objects in the quarantine, which still take their slots
+    pub quarantined: usize,
+    // bytes of a page not holding objects: the page header, the slot headers, redzones,
+    // padding and the tail
+    pub wasted_per_page: usize,
+    // lifetime counters. failures are allocations the page allocator could not serve.
+    pub allocs: usize,
+    pub frees: usize,
+    pub failures: usize,
+}
+
+// corrupted slot found by `verify`, with the address of its payload. WrittenAfterFree needs
+// the poison feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlabCorruption {
+    Redzone(usize),
+    WrittenAfterFree(usize),
+    BadMagic(usize),
+}
+
+// iterates pages of the partial list, the full list, then the empty list
+struct Slots<'a> {
+    layout: &'a SlotLayout,
+    page: *mut PageLink,
+    next_lists: [*mut PageLink; 2],
+    index: usize,
+}
+
+pub struct RawObjects<'a> {
+    slots: Slots<'a>,
+}
+
+pub struct Objects<'a, T> {
+    objects: RawObjects<'a>,
+    _phantom: PhantomData<&'a T>,
+}
+
+// placement of an object in its slot: SlotObject header, redzone, payload, redzone, and of the
+// slots in a slab page of 2^order pages. free payloads are filled with UNUSED_FILL if `poison`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SlotLayout {
+    size: usize,
+    align: usize,
+    order: u32,
+    poison: bool,
+}
+
 struct PageList {
     head: *mut PageLink,
     tail: *mut PageLink,
@@ -41,18 +178,21 @@ struct PageLink {
 }
 
 #[repr(C)]
-struct SlotPage<T> {
+struct SlotPage {
     link: PageLink,
     free_index: u16,
     alloc_count: u16,
-    _phantom: PhantomData<T>,
+    // offset of the first slot, shifted by the color of the page
+    first: u16,
 }
 
 #[repr(C)]
-struct SlotObject<T> {
+struct SlotObject {
     magic: u16,
     next: u16,
-    _phantom: PhantomData<T>,
+    // offset from the SlotPage header, which is not found by masking the address because slab
+    // pages are aligned in PAGE_SIZE only
+    offset: u16,
 }
 
 const fn align_ceil(x: usize, align: usize) -> usize {
@@ -60,100 +200,206 @@ const fn align_ceil(x: usize, align: usize) -> usize {
     (x + mask) & !mask
 }
 
-impl<T> SlotObject<T> {
-    const fn align_of() -> usize {
-        let a = align_of::<SlotObject<T>>();
-        let b = align_of::<T>();
-        if a > b { a } else { b }
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+impl SlotLayout {
+    const fn new(size: usize, align: usize) -> Self {
+        Self { size, align, order: 0, poison: POISON }
+    }
+
+    const fn of<T>() -> Self {
+        Self::new(size_of::<T>(), align_of::<T>())
+    }
+
+    const fn with_order(self, order: u32) -> Self {
+        Self { order, ..self }
+    }
+
+    // the lowest order up to `max_order` whose slab pages waste at most 1/16 in their tail, or
+    // else the one wasting the least for its size. the order stays 0 if nothing fits.
+    const fn with_best_order(self, max_order: u32) -> Self {
+        let max_order = if max_order < MAX_SLAB_ORDER { max_order } else { MAX_SLAB_ORDER };
+        let mut best = self.with_order(0);
+        let mut best_fits = false;
+
+        let mut order = 0;
+        while order <= max_order {
+            let layout = self.with_order(order);
+            if layout.fits() {
+                let tail = layout.tail_size();
+                if tail * 16 <= layout.page_size() {
+                    return layout;
+                }
+                // tail / page_size < best tail / best page_size
+                if !best_fits || tail * best.page_size() < best.tail_size() * layout.page_size() {
+                    best = layout;
+                    best_fits = true;
+                }
+            }
+            order += 1;
+        }
+        best
+    }
+
+    const fn page_size(&self) -> usize {
+        PAGE_SIZE << self.order
+    }
+
+    const fn align_of(&self) -> usize {
+        max(align_of::<SlotObject>(), self.align)
+    }
+    const fn redzone1_offset(&self) -> usize {
+        size_of::<SlotObject>()
+    }
+    const fn redzone1_size(&self) -> usize {
+        self.payload_offset() - self.redzone1_offset()
+    }
+    const fn payload_offset(&self) -> usize {
+        align_ceil(self.redzone1_offset() + REDZONE_SIZE as usize, self.align)
+    }
+    const fn redzone2_offset(&self) -> usize {
+        self.payload_offset() + self.size
     }
-    const fn redzone1_offset() -> usize {
-        size_of::<SlotObject<T>>()
+    const fn redzone2_size(&self) -> usize {
+        self.size_of() - self.redzone2_offset()
     }
-    const fn redzone1_size() -> usize {
-        Self::payload_offset() - Self::redzone1_offset()
+    const fn size_of(&self) -> usize {
+        align_ceil(self.redzone2_offset() + REDZONE_SIZE as usize, self.align_of())
     }
-    const fn payload_offset() -> usize {
-        align_ceil(Self::redzone1_offset() + REDZONE_SIZE as usize, align_of::<T>())
+
+    // offset of the first object in a page
+    const fn object_offset(&self) -> usize {
+        align_ceil(size_of::<SlotPage>(), self.align_of())
     }
-    const fn redzone2_offset() -> usize {
-        Self::payload_offset() + size_of::<T>()
+
+    const fn fits(&self) -> bool {
+        self.order <= MAX_SLAB_ORDER && self.object_offset() + self.size_of() <= self.page_size()
     }
-    const fn redzone2_size() -> usize {
-        Self::size_of() - Self::redzone2_offset()
+
+    const fn objects_per_page(&self) -> usize {
+        (self.page_size() - self.object_offset()) / self.size_of()
     }
-    const fn size_of() -> usize {
-        align_ceil(Self::redzone2_offset() + REDZONE_SIZE as usize, Self::align_of())
+
+    // bytes after the last slot of a page
+    const fn tail_size(&self) -> usize {
+        self.page_size() - self.object_offset() - self.objects_per_page() * self.size_of()
     }
 
-    fn init(&mut self) {
+    // first objects are shifted by multiples of this, keeping the alignment of the slots
+    const fn color_step(&self) -> usize {
+        max(CACHE_LINE_SIZE, self.align_of())
+    }
+
+    // number of distinct first object offsets fitting in the tail
+    const fn colors(&self) -> usize {
+        self.tail_size() / self.color_step() + 1
+    }
+}
+
+impl SlotObject {
+    fn init(&mut self, layout: &SlotLayout, offset: u16) {
         let raw = self as *mut Self as *mut u8;
         self.magic = EMPTY_MAGIC;
         self.next = 0;
+        self.offset = offset;
         unsafe {
-            write_bytes(raw.add(Self::redzone1_offset()), REDZONE_FILL, Self::redzone1_size());
-            write_bytes(raw.add(Self::payload_offset()), UNUSED_FILL, size_of::<T>());
-            write_bytes(raw.add(Self::redzone2_offset()), REDZONE_FILL, Self::redzone2_size());
+            if REDZONE {
+                write_bytes(raw.add(layout.redzone1_offset()), REDZONE_FILL, layout.redzone1_size());
+                write_bytes(raw.add(layout.redzone2_offset()), REDZONE_FILL, layout.redzone2_size());
+            }
+            if layout.poison {
+                write_bytes(raw.add(layout.payload_offset()), UNUSED_FILL, layout.size);
+            }
         }
     }
 
-    fn check_redzone(&self) -> bool {
+    // always true without the redzone feature
+    fn check_redzone(&self, layout: &SlotLayout) -> bool {
+        if !REDZONE {
+            return true;
+        }
+
         let raw = self as *const Self as *const u8;
         let redzone1 = unsafe {
-            from_raw_parts(raw.add(Self::redzone1_offset()), Self::redzone1_size())
+            from_raw_parts(raw.add(layout.redzone1_offset()), layout.redzone1_size())
         };
         let redzone2 = unsafe {
-            from_raw_parts(raw.add(Self::redzone2_offset()), Self::redzone2_size())
+            from_raw_parts(raw.add(layout.redzone2_offset()), layout.redzone2_size())
         };
 
         redzone1.iter().all(|&b| b == REDZONE_FILL) &&
             redzone2.iter().all(|&b| b == REDZONE_FILL)
     }
 
-    fn check_unused(&self) -> bool {
+    // always true without the poison feature or with a constructor
+    fn check_unused(&self, layout: &SlotLayout) -> bool {
+        if !layout.poison {
+            return true;
+        }
+
         let raw = self as *const Self as *const u8;
         let payload = unsafe {
-            from_raw_parts(raw.add(Self::payload_offset()), size_of::<T>())
+            from_raw_parts(raw.add(layout.payload_offset()), layout.size)
         };
 
         payload.iter().all(|&b| b == UNUSED_FILL)
     }
 
-    fn write_unused(&mut self, b: u8) {
+    fn write_unused(&mut self, layout: &SlotLayout, b: u8) {
         let raw = self as *mut Self as *mut u8;
         unsafe {
-            write_bytes(raw.add(Self::payload_offset()), b, size_of::<T>());
+            write_bytes(raw.add(layout.payload_offset()), b, layout.size);
         }
     }
 
-    fn payload(&mut self) -> NonNull<T> {
-        let raw = self as *mut Self as *mut u8;
-        unsafe { NonNull::new_unchecked(raw.add(Self::payload_offset()) as *mut T) }
+    fn payload(&self, layout: &SlotLayout) -> NonNull<u8> {
+        let raw = self as *const Self as *mut u8;
+        unsafe { NonNull::new_unchecked(raw.add(layout.payload_offset())) }
     }
 
-    fn on_alloc(&mut self) {
-        assert!(self.magic == EMPTY_MAGIC && self.next == 0, "slab is poisoned");
-        assert!(self.check_redzone(), "redzone is corrupted");
-        assert!(self.check_unused(), "slab is poisoned");
-        (*self).magic = OBJECT_MAGIC;
-        (*self).write_unused(0);
+    // the checks panic with the address of the object and the name of the slab
+    fn on_alloc(&mut self, layout: &SlotLayout, name: &str) {
+        let addr = self.payload(layout).as_ptr();
+        assert!(self.magic == EMPTY_MAGIC && self.next == 0, "slab is poisoned: object {:p} in {}", addr, name);
+        assert!(self.check_redzone(layout), "redzone is corrupted: object {:p} in {}", addr, name);
+        assert!(self.check_unused(layout), "slab is poisoned: object {:p} in {}", addr, name);
+        self.magic = OBJECT_MAGIC;
+        if layout.poison {
+            self.write_unused(layout, 0);
+        }
     }
 
-    fn on_dealloc(&mut self) {
-        assert!(self.magic == OBJECT_MAGIC && self.next == 0, "try to deallocate an object that is not allocated");
-        assert!(self.check_redzone(), "redzone is corrupted");
-        (*self).magic = EMPTY_MAGIC;
-        (*self).write_unused(UNUSED_FILL);
+    // the object goes to the quarantine if enabled
+    fn on_dealloc(&mut self, layout: &SlotLayout, name: &str) {
+        let addr = self.payload(layout).as_ptr();
+        assert!(self.magic == OBJECT_MAGIC && self.next == 0, "try to deallocate an object that is not allocated: object {:p} in {}", addr, name);
+        assert!(self.check_redzone(layout), "redzone is corrupted: object {:p} in {}", addr, name);
+        self.magic = if QUARANTINE { QUARANTINE_MAGIC } else { EMPTY_MAGIC };
+        if layout.poison {
+            self.write_unused(layout, UNUSED_FILL);
+        }
+    }
+
+    #[cfg(feature = "quarantine")]
+    fn on_leave_quarantine(&mut self, layout: &SlotLayout, name: &str) {
+        let addr = self.payload(layout).as_ptr();
+        assert!(self.magic == QUARANTINE_MAGIC, "slab is poisoned: object {:p} in {}", addr, name);
+        assert!(self.check_unused(layout), "object is written after free: object {:p} in {}", addr, name);
+        assert!(self.check_redzone(layout), "redzone is corrupted: object {:p} in {}", addr, name);
+        self.magic = EMPTY_MAGIC;
     }
 
     // Safety: `self` is a valid object inside page
-    unsafe fn page_from_object(&mut self) -> *mut SlotPage<T> {
-        let raw = self as *mut SlotObject<T> as usize;
-        (raw & !(PAGE_SIZE as usize - 1)) as *mut SlotPage<T>
+    unsafe fn page_from_object(&mut self) -> *mut SlotPage {
+        let raw = self as *mut SlotObject as usize;
+        (raw - self.offset as usize) as *mut SlotPage
     }
 }
 
 impl PageLink {
-    fn null() -> Self {
+    const fn null() -> Self {
         PageLink {
             next: null_mut(),
             prev: null_mut(),
@@ -162,7 +408,7 @@ impl PageLink {
 }
 
 impl PageList {
-    fn new() -> Self {
+    const fn new() -> Self {
         PageList {
             head: null_mut(),
             tail: null_mut(),
@@ -188,6 +434,15 @@ impl PageList {
         self.tail = link;
     }
 
+    // returns null if the list is empty
+    fn pop_front(&mut self) -> *mut PageLink {
+        let link = self.head;
+        if !link.is_null() {
+            unsafe { self.remove(&mut *link); }
+        }
+        link
+    }
+
     // Safety: `link` must be a valid link in the list
     unsafe fn remove(&mut self, link: &mut PageLink) {
         if link.prev.is_null() {
@@ -207,53 +462,49 @@ impl PageList {
     }
 }
 
-impl<T> SlotPage<T> {
-    const SIZE_ASSERT: () = assert!(Self::object_offset() + SlotObject::<T>::size_of() <= PAGE_SIZE, "object size is too big for a page");
-
-    const fn object_offset() -> usize {
-        align_ceil(size_of::<SlotPage<T>>(), SlotObject::<T>::align_of())
-    }
-
-    // Safety: `addr` must be aligned to PAGE_SIZE and point to a valid memory region sized of PAGE_SIZE
-    unsafe fn init(addr: usize) -> *mut SlotPage<T> {
-        let header = addr as *mut SlotPage<T>;
+impl SlotPage {
+    // the first slot is shifted by `color` steps, and `ctor` runs on every payload
+    // Safety: `addr` must be aligned to PAGE_SIZE and point to a valid memory region sized of
+    // `layout.page_size()`, and `color` must be less than `layout.colors()`
+    unsafe fn init(addr: usize, layout: &SlotLayout, color: usize, ctor: Option<fn(NonNull<u8>)>) -> *mut SlotPage {
+        let header = addr as *mut SlotPage;
 
-        let mut offset = Self::object_offset();
+        let first = layout.object_offset() + color * layout.color_step();
+        let obj_size = layout.size_of();
+        let count = layout.objects_per_page();
 
         unsafe {
             core::ptr::write(header, SlotPage {
                 link: PageLink::null(),
-                free_index: offset as u16,
+                free_index: first as u16,
                 alloc_count: 0,
-                _phantom: PhantomData,
+                first: first as u16,
             });
 
-            loop {
-                let obj_addr = addr + offset;
-                let obj_size = SlotObject::<T>::size_of();
-                let next_offset = offset + obj_size;
-
-                let obj = obj_addr as *mut SlotObject<T>;
-                (*obj).init();
-
-                if next_offset + obj_size < PAGE_SIZE {
-                    (*obj).next = next_offset as u16;
-                    offset = next_offset;
-                } else {
-                    (*obj).next = 0;
-                    break;
+            for idx in 0..count {
+                let offset = first + idx * obj_size;
+                let obj = (addr + offset) as *mut SlotObject;
+                (*obj).init(layout, offset as u16);
+                (*obj).next = if idx + 1 < count { (offset + obj_size) as u16 } else { 0 };
+                if let Some(ctor) = ctor {
+                    ctor((*obj).payload(layout));
                 }
             }
         }
         header
     }
 
-    fn pop_front_object(&mut self) -> (*mut SlotObject<T>, bool) {
+    fn slot(&self, layout: &SlotLayout, index: usize) -> *mut SlotObject {
+        let page_addr = self as *const SlotPage as usize;
+        (page_addr + self.first as usize + index * layout.size_of()) as *mut SlotObject
+    }
+
+    fn pop_front_object(&mut self) -> (*mut SlotObject, bool) {
         assert!(self.free_index != 0, "slab is corrupted: try to pop object from an fully-allocated page");
 
-        let page_addr = self as *mut SlotPage<T> as usize;
+        let page_addr = self as *mut SlotPage as usize;
         let obj_addr = page_addr + self.free_index as usize;
-        let obj = obj_addr as *mut SlotObject<T>;
+        let obj = obj_addr as *mut SlotObject;
 
         unsafe {
             let full =
@@ -272,8 +523,8 @@ impl<T> SlotPage<T> {
     }
 
     // Safety: `obj` must be a valid object inside page
-    unsafe fn push_front_object(&mut self, obj: *mut SlotObject<T>) {
-        let page_addr = self as *mut SlotPage<T> as usize;
+    unsafe fn push_front_object(&mut self, obj: *mut SlotObject) {
+        let page_addr = self as *mut SlotPage as usize;
         let obj_addr = obj as usize;
         unsafe { (*obj).next = self.free_index; }
         self.free_index = (obj_addr - page_addr) as u16;
@@ -281,72 +532,395 @@ impl<T> SlotPage<T> {
     }
 }
 
-impl<T, PA: PageAllocator> SlabAllocator<T, PA> {
-    pub fn new(page_allocator: PA) -> Self {
-        let _ = SlotPage::<T>::SIZE_ASSERT; // Ensure that the object fits in a page
+impl RawSlab {
+    // `layout` must fit in its slab page
+    fn new(name: &'static str, layout: SlotLayout) -> Self {
         Self {
+            name,
+            layout,
+            ctor: None,
+            dtor: None,
+            next_color: 0,
             partial_list: PageList::new(),
-            page_allocator,
-            _phantom: PhantomData,
+            full_list: PageList::new(),
+            empty_list: PageList::new(),
+            empty_limit: 0,
+            page_count: 0,
+            full_count: 0,
+            empty_count: 0,
+            allocs: 0,
+            frees: 0,
+            failures: 0,
+            #[cfg(feature = "quarantine")]
+            quarantine: Quarantine::new(),
         }
     }
 
-    pub fn alloc(&mut self) -> Option<NonNull<T>> {
+    fn quarantined(&self) -> usize {
+        #[cfg(feature = "quarantine")]
+        return self.quarantine.len;
+        #[cfg(not(feature = "quarantine"))]
+        return 0;
+    }
+
+    fn stats(&self) -> SlabStats {
+        let objects_per_page = self.layout.objects_per_page();
+        SlabStats {
+            object_size: self.layout.size,
+            slot_size: self.layout.size_of(),
+            order: self.layout.order,
+            objects_per_page,
+            pages: self.page_count,
+            full_pages: self.full_count,
+            partial_pages: self.page_count - self.full_count - self.empty_count,
+            empty_pages: self.empty_count,
+            live: self.allocs - self.frees,
+            quarantined: self.quarantined(),
+            wasted_per_page: self.layout.page_size() - objects_per_page * self.layout.size,
+            allocs: self.allocs,
+            frees: self.frees,
+            failures: self.failures,
+        }
+    }
+
+    fn alloc(&mut self, page_allocator: &mut impl PageAllocator) -> Option<NonNull<u8>> {
         if self.partial_list.head.is_null() {
-            self.alloc_page()?;
+            if !self.empty_list.head.is_null() {
+                let link = self.empty_list.pop_front();
+                self.empty_count -= 1;
+                unsafe { self.partial_list.assign_singleton(&mut *link); }
+            }
+            else if self.alloc_page(page_allocator).is_none() {
+                self.failures += 1;
+                return None;
+            }
         }
 
-        let page = unsafe { &mut *(self.partial_list.head as *mut SlotPage<T>) };
+        let page = unsafe { &mut *(self.partial_list.head as *mut SlotPage) };
         let (obj, full) = page.pop_front_object();
 
         if full {
             unsafe { self.partial_list.remove(&mut page.link); }
+            self.full_list.push_back(&mut page.link);
+            self.full_count += 1;
         }
+        self.allocs += 1;
 
         unsafe {
-            (*obj).on_alloc();
-            Some((*obj).payload())
+            (*obj).on_alloc(&self.layout, self.name);
+            Some((*obj).payload(&self.layout))
         }
     }
 
-    fn alloc_page(&mut self) -> Option<()> {
-        let page_ptr = self.page_allocator.allocate()?;
+    fn alloc_page(&mut self, page_allocator: &mut impl PageAllocator) -> Option<()> {
+        let page_ptr = page_allocator.allocate_order(self.layout.order)?;
         let page_addr = page_ptr.as_ptr() as usize;
         unsafe {
-            let page = &mut *SlotPage::<T>::init(page_addr);
+            let page = &mut *SlotPage::init(page_addr, &self.layout, self.next_color, self.ctor);
             self.partial_list.assign_singleton(&mut page.link);
         }
+        self.next_color = (self.next_color + 1) % self.layout.colors();
+        self.page_count += 1;
         Some(())
     }
 
-    // Safety: `ptr` must be a valid pointer to an allocated object
-    pub unsafe fn dealloc(&mut self, ptr: NonNull<T>) {
+    // Safety: `ptr` must be a valid pointer to an object allocated from this slab
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, page_allocator: &mut impl PageAllocator) {
         let payload_addr = ptr.as_ptr() as usize;
-        let obj_addr = payload_addr - SlotObject::<T>::payload_offset();
+        let obj = (payload_addr - self.layout.payload_offset()) as *mut SlotObject;
+
+        unsafe {
+            (*obj).on_dealloc(&self.layout, self.name);
+        }
+        self.frees += 1;
+
+        // the oldest object in the quarantine is freed instead
+        #[cfg(feature = "quarantine")]
+        let Some(obj) = self.quarantine.push(obj) else {
+            return;
+        };
+        #[cfg(feature = "quarantine")]
+        unsafe {
+            (*obj).on_leave_quarantine(&self.layout, self.name);
+        }
 
         unsafe {
-            let obj = obj_addr as *mut SlotObject<T>;
-            (*obj).on_dealloc();
+            self.free_slot(obj, page_allocator);
+        }
+    }
 
+    // put a freed object back in its page
+    // Safety: `obj` must be a slot of this slab, which is not allocated nor in the quarantine
+    unsafe fn free_slot(&mut self, obj: *mut SlotObject, page_allocator: &mut impl PageAllocator) {
+        unsafe {
             let page = (*obj).page_from_object();
             let was_full = (*page).free_index == 0;
             (*page).push_front_object(obj);
+            if was_full {
+                self.full_list.remove(&mut (*page).link);
+                self.full_count -= 1;
+            }
 
             if (*page).alloc_count == 0 {
                 if !was_full {
                     self.partial_list.remove(&mut (*page).link);
                 }
-                self.page_allocator.deallocate(NonNull::new_unchecked(page as *mut [u8; PAGE_SIZE]));
+                if self.empty_count < self.empty_limit {
+                    self.empty_list.push_back(&mut (*page).link);
+                    self.empty_count += 1;
+                }
+                else {
+                    self.release_page(page, page_allocator);
+                }
             }
             else if was_full {
                 self.partial_list.push_back(&mut (*page).link);
             }
         }
     }
+
+    // Safety: `page` must be a page of this slab in none of the lists
+    unsafe fn release_page(&mut self, page: *mut SlotPage, page_allocator: &mut impl PageAllocator) {
+        if let Some(dtor) = self.dtor {
+            for idx in 0..self.layout.objects_per_page() {
+                unsafe { dtor((*(*page).slot(&self.layout, idx)).payload(&self.layout)); }
+            }
+        }
+        unsafe {
+            page_allocator.deallocate_order(NonNull::new_unchecked(page as *mut u8), self.layout.order);
+        }
+        self.page_count -= 1;
+    }
+
+    // keep at most `limit` empty pages, releasing the extra ones now
+    fn set_empty_limit(&mut self, limit: usize, page_allocator: &mut impl PageAllocator) {
+        self.empty_limit = limit;
+        while self.empty_count > limit {
+            let link = self.empty_list.pop_front();
+            self.empty_count -= 1;
+            unsafe { self.release_page(link as *mut SlotPage, page_allocator); }
+        }
+    }
+
+    // free every object in the quarantine now
+    #[cfg(feature = "quarantine")]
+    fn flush_quarantine(&mut self, page_allocator: &mut impl PageAllocator) {
+        while let Some(obj) = self.quarantine.pop() {
+            unsafe {
+                (*obj).on_leave_quarantine(&self.layout, self.name);
+                self.free_slot(obj, page_allocator);
+            }
+        }
+    }
+
+    // release all empty pages after flushing the quarantine, and returns how many
+    fn shrink(&mut self, page_allocator: &mut impl PageAllocator) -> usize {
+        #[cfg(feature = "quarantine")]
+        self.flush_quarantine(page_allocator);
+
+        let count = self.empty_count;
+        let limit = self.empty_limit;
+        self.set_empty_limit(0, page_allocator);
+        self.empty_limit = limit;
+        count
+    }
+
+    // every slot of every page, free or not
+    fn slots(&self) -> Slots<'_> {
+        Slots {
+            layout: &self.layout,
+            page: self.partial_list.head,
+            next_lists: [self.full_list.head, self.empty_list.head],
+            index: 0,
+        }
+    }
+
+    fn objects(&self) -> RawObjects<'_> {
+        RawObjects { slots: self.slots() }
+    }
+
+    // check the redzones of every slot, and that free slots are not written after being freed
+    fn verify(&self) -> Result<(), SlabCorruption> {
+        for slot in self.slots() {
+            let obj = unsafe { &*slot };
+            let addr = slot as usize + self.layout.payload_offset();
+            match obj.magic {
+                OBJECT_MAGIC => {}
+                EMPTY_MAGIC | QUARANTINE_MAGIC => {
+                    if !obj.check_unused(&self.layout) {
+                        return Err(SlabCorruption::WrittenAfterFree(addr));
+                    }
+                }
+                _ => return Err(SlabCorruption::BadMagic(addr)),
+            }
+            if !obj.check_redzone(&self.layout) {
+                return Err(SlabCorruption::Redzone(addr));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "quarantine")]
+impl Quarantine {
+    const fn new() -> Self {
+        Self {
+            slots: [null_mut(); QUARANTINE_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    // returns the oldest object if the quarantine was full
+    fn push(&mut self, obj: *mut SlotObject) -> Option<*mut SlotObject> {
+        let oldest = if self.len == QUARANTINE_SIZE { self.pop() } else { None };
+        self.slots[(self.head + self.len) % QUARANTINE_SIZE] = obj;
+        self.len += 1;
+        oldest
+    }
+
+    fn pop(&mut self) -> Option<*mut SlotObject> {
+        if self.len == 0 {
+            return None;
+        }
+        let obj = self.slots[self.head];
+        self.head = (self.head + 1) % QUARANTINE_SIZE;
+        self.len -= 1;
+        Some(obj)
+    }
+}
+
+impl<'a> Iterator for Slots<'a> {
+    type Item = *mut SlotObject;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.page.is_null() {
+                let next = self.next_lists.iter_mut().find(|x| !x.is_null())?;
+                self.page = core::mem::replace(next, null_mut());
+                self.index = 0;
+            }
+
+            if self.index < self.layout.objects_per_page() {
+                let slot = unsafe { (*(self.page as *mut SlotPage)).slot(self.layout, self.index) };
+                self.index += 1;
+                return Some(slot);
+            }
+
+            // the link is at the start of the page
+            self.page = unsafe { (*self.page).next };
+            self.index = 0;
+        }
+    }
+}
+
+impl<'a> Iterator for RawObjects<'a> {
+    type Item = NonNull<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let layout = self.slots.layout;
+        self.slots
+            .find(|&slot| unsafe { (*slot).magic == OBJECT_MAGIC })
+            .map(|slot| unsafe { (*slot).payload(layout) })
+    }
+}
+
+impl<'a, T> Iterator for Objects<'a, T> {
+    type Item = NonNull<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.objects.next().map(NonNull::cast)
+    }
+}
+
+impl<T, PA: PageAllocator> SlabAllocator<T, PA> {
+    const LAYOUT: SlotLayout = SlotLayout::of::<T>().with_best_order(PA::MAX_ORDER);
+    const SIZE_ASSERT: () = assert!(Self::LAYOUT.fits(), "object size is too big for a slab page");
+
+    // objects larger than a page get slab pages of several pages if the page allocator serves
+    // them, see `PageAllocator::MAX_ORDER`
+    pub fn new(page_allocator: PA) -> Self {
+        const { Self::SIZE_ASSERT }; // Ensure that the object fits in a slab page
+        Self {
+            slab: RawSlab::new(core::any::type_name::<T>(), Self::LAYOUT),
+            page_allocator,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn alloc(&mut self) -> Option<NonNull<T>> {
+        self.slab.alloc(&mut self.page_allocator).map(NonNull::cast)
+    }
+
+    // Safety: `ptr` must be a valid pointer to an allocated object
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<T>) {
+        unsafe {
+            self.slab.dealloc(ptr.cast(), &mut self.page_allocator);
+        }
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.slab.stats()
+    }
+
+    // Bonwick-style constructed objects: `ctor` runs on every object when its page is taken from
+    // the page allocator, and `dtor` before the page is returned. objects must be back in their
+    // constructed state when freed, and they are not poisoned.
+    pub fn with_ctor(page_allocator: PA, ctor: fn(NonNull<T>), dtor: Option<fn(NonNull<T>)>) -> Self {
+        let mut slab = Self::new(page_allocator);
+        // Safety: NonNull<T> and NonNull<u8> are ABI compatible pointers
+        unsafe {
+            slab.slab.ctor = Some(core::mem::transmute::<fn(NonNull<T>), fn(NonNull<u8>)>(ctor));
+            slab.slab.dtor = dtor.map(|x| core::mem::transmute::<fn(NonNull<T>), fn(NonNull<u8>)>(x));
+        }
+        slab.slab.layout.poison = false;
+        slab
+    }
+
+    // name in the messages of corruption panics, the type name by default
+    pub fn set_name(&mut self, name: &'static str) {
+        self.slab.name = name;
+    }
+
+    // keep up to `limit` pages without objects instead of returning them to the page allocator
+    // at once, so that allocating and freeing around a page boundary does not take and return
+    // the same page over and over. the default is 0.
+    pub fn set_empty_limit(&mut self, limit: usize) {
+        self.slab.set_empty_limit(limit, &mut self.page_allocator);
+    }
+
+    // return the kept empty pages to the page allocator after flushing the quarantine,
+    // and returns how many
+    pub fn shrink(&mut self) -> usize {
+        self.slab.shrink(&mut self.page_allocator)
+    }
+
+    // all allocated objects. the slab cannot change while iterating, but dereferencing the
+    // pointers is up to the caller, who knows whether the objects are in use elsewhere.
+    pub fn objects(&self) -> Objects<'_, T> {
+        Objects {
+            objects: self.slab.objects(),
+            _phantom: PhantomData,
+        }
+    }
+
+    // check every object now instead of on its next alloc or dealloc
+    pub fn verify(&self) -> Result<(), SlabCorruption> {
+        self.slab.verify()
+    }
 }
 
+#[cfg(test)]
+pub(crate) mod test_support;
+
 #[cfg(test)]
 mod test_slab;
 
 #[cfg(test)]
 mod test_pagelist;
+
+#[cfg(test)]
+mod test_size_class;
+
+#[cfg(test)]
+mod test_magazine;
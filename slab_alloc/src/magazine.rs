@@ -0,0 +1,141 @@
+use core::marker::PhantomData;
+use core::ptr::{NonNull, null_mut};
+use spin::Mutex;
+
+use crate::{PageAllocator, SlabAllocator, SlabStats};
+
+// objects a magazine holds, and how many move between a magazine and the slab at once
+pub const MAGAZINE_SIZE: usize = 32;
+pub const MAGAZINE_BATCH: usize = MAGAZINE_SIZE / 2;
+
+// stack of free objects owned by one CPU. the objects are allocated in the slab, so the debug
+// checks of the slab see them only when they go back to it.
+pub struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    len: usize,
+}
+
+unsafe impl Send for Magazine {}
+
+// storage of one magazine per CPU.
+/// # Safety
+/// `with_current` must give `f` the magazine of the current CPU, and nobody else may use it until
+/// `f` returns, i.e. the task is not moved to another CPU and no interrupt handler allocates from
+/// the same cache meanwhile.
+pub unsafe trait PerCpu {
+    fn with_current<R>(&self, f: impl FnOnce(&mut Magazine) -> R) -> R;
+    // every magazine, while no CPU uses them
+    fn for_each(&mut self, f: impl FnMut(&mut Magazine));
+}
+
+// magazines in front of a shared `SlabAllocator`: allocations and frees go to the magazine of
+// the current CPU, and only refilling an empty one or spilling a full one takes the lock
+pub struct MagazineAllocator<T, PA: PageAllocator, C: PerCpu> {
+    slab: Mutex<SlabAllocator<T, PA>>,
+    cpus: C,
+    _phantom: PhantomData<T>,
+}
+
+unsafe impl<T, PA: PageAllocator, C: PerCpu + Send> Send for MagazineAllocator<T, PA, C> {}
+unsafe impl<T, PA: PageAllocator, C: PerCpu + Sync> Sync for MagazineAllocator<T, PA, C> {}
+
+impl Magazine {
+    pub const fn new() -> Self {
+        Self {
+            objects: [null_mut(); MAGAZINE_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == MAGAZINE_SIZE
+    }
+
+    fn push(&mut self, ptr: NonNull<u8>) {
+        self.objects[self.len] = ptr.as_ptr();
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        NonNull::new(self.objects[self.len])
+    }
+}
+
+impl Default for Magazine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, PA: PageAllocator, C: PerCpu> MagazineAllocator<T, PA, C> {
+    pub fn new(slab: SlabAllocator<T, PA>, cpus: C) -> Self {
+        Self {
+            slab: Mutex::new(slab),
+            cpus,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn alloc(&self) -> Option<NonNull<T>> {
+        self.cpus.with_current(|magazine| {
+            if magazine.is_empty() {
+                let mut slab = self.slab.lock();
+                while magazine.len() < MAGAZINE_BATCH {
+                    match slab.alloc() {
+                        Some(ptr) => magazine.push(ptr.cast()),
+                        None => break,
+                    }
+                }
+            }
+            magazine.pop().map(NonNull::cast)
+        })
+    }
+
+    /// # Safety
+    /// `ptr` must be a valid pointer to an object allocated by `alloc` of this allocator, which
+    /// is not used after
+    pub unsafe fn dealloc(&self, ptr: NonNull<T>) {
+        self.cpus.with_current(|magazine| {
+            if magazine.is_full() {
+                let mut slab = self.slab.lock();
+                for _ in 0..MAGAZINE_BATCH {
+                    let obj = magazine.pop().unwrap();
+                    unsafe { slab.dealloc(obj.cast()); }
+                }
+            }
+            magazine.push(ptr.cast());
+        })
+    }
+
+    // return the objects of every magazine to the slab
+    pub fn drain(&mut self) {
+        let slab = self.slab.get_mut();
+        self.cpus.for_each(|magazine| {
+            while let Some(obj) = magazine.pop() {
+                unsafe { slab.dealloc(obj.cast()); }
+            }
+        });
+    }
+
+    // stats of the slab, where the objects in magazines are live
+    pub fn stats(&self) -> SlabStats {
+        self.slab.lock().stats()
+    }
+
+    // run `f` on the shared slab under the lock
+    pub fn with_slab<R>(&self, f: impl FnOnce(&mut SlabAllocator<T, PA>) -> R) -> R {
+        f(&mut self.slab.lock())
+    }
+}
@@ -0,0 +1,105 @@
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+use crate::{PageAllocator, RawObjects, RawSlab, SlabCorruption, SlabStats, SlotLayout};
+
+// powers of two and the 1.5x steps between them
+pub const SIZE_CLASSES: [usize; 15] = [16, 24, 32, 48, 64, 96, 128, 192, 256, 384, 512, 768, 1024, 1536, 2048];
+
+// names of the slabs of SIZE_CLASSES
+pub const SIZE_CLASS_NAMES: [&str; 15] = [
+    "size-16", "size-24", "size-32", "size-48", "size-64", "size-96", "size-128", "size-192",
+    "size-256", "size-384", "size-512", "size-768", "size-1024", "size-1536", "size-2048",
+];
+
+// objects of every size class are aligned in this
+pub const SIZE_CLASS_ALIGN: usize = 16;
+
+const CLASS_COUNT: usize = SIZE_CLASSES.len();
+
+// untyped allocator serving arbitrary layouts from one slab per size class, all taking pages
+// from the same page allocator
+pub struct SizeClassAllocator<PA: PageAllocator> {
+    slabs: [RawSlab; CLASS_COUNT],
+    page_allocator: PA,
+}
+
+unsafe impl<PA: PageAllocator> Send for SizeClassAllocator<PA> {}
+
+impl<PA: PageAllocator> SizeClassAllocator<PA> {
+    const SIZE_ASSERT: () = {
+        let mut idx = 0;
+        while idx < CLASS_COUNT {
+            assert!(class_layout(idx, PA::MAX_ORDER).fits(), "size class is too big for a slab page");
+            idx += 1;
+        }
+    };
+
+    pub fn new(page_allocator: PA) -> Self {
+        const { Self::SIZE_ASSERT };
+        Self {
+            slabs: core::array::from_fn(|idx| RawSlab::new(SIZE_CLASS_NAMES[idx], class_layout(idx, PA::MAX_ORDER))),
+            page_allocator,
+        }
+    }
+
+    // index in SIZE_CLASSES serving `layout`, or None if it is too large or too aligned
+    pub fn class_index(layout: Layout) -> Option<usize> {
+        if layout.align() > SIZE_CLASS_ALIGN {
+            return None;
+        }
+        SIZE_CLASSES.iter().position(|&size| layout.size() <= size)
+    }
+
+    // returns None if no size class serves `layout`, or if the page allocator fails
+    pub fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let class = Self::class_index(layout)?;
+        self.slabs[class].alloc(&mut self.page_allocator)
+    }
+
+    /// # Safety
+    /// `ptr` must be allocated by `alloc` of this allocator with the same `layout`, and not used
+    /// after
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let class = Self::class_index(layout).expect("layout is not served by any size class");
+        unsafe {
+            self.slabs[class].dealloc(ptr, &mut self.page_allocator);
+        }
+    }
+
+    // see `SlabAllocator::set_empty_limit`, applied to every class
+    pub fn set_empty_limit(&mut self, limit: usize) {
+        for slab in &mut self.slabs {
+            slab.set_empty_limit(limit, &mut self.page_allocator);
+        }
+    }
+
+    // see `SlabAllocator::shrink`
+    pub fn shrink(&mut self) -> usize {
+        self.slabs.iter_mut().map(|slab| slab.shrink(&mut self.page_allocator)).sum()
+    }
+
+    pub fn page_allocator(&self) -> &PA {
+        &self.page_allocator
+    }
+
+    // `class` is an index in SIZE_CLASSES
+    pub fn stats(&self, class: usize) -> SlabStats {
+        self.slabs[class].stats()
+    }
+
+    // allocated objects of `class`, an index in SIZE_CLASSES
+    pub fn objects(&self, class: usize) -> RawObjects<'_> {
+        self.slabs[class].objects()
+    }
+
+    // check every object of every class, see `SlabAllocator::verify`
+    pub fn verify(&self) -> Result<(), SlabCorruption> {
+        self.slabs.iter().try_for_each(RawSlab::verify)
+    }
+}
+
+// large classes get slab pages of several pages if the page allocator serves them
+const fn class_layout(class: usize, max_order: u32) -> SlotLayout {
+    SlotLayout::new(SIZE_CLASSES[class], SIZE_CLASS_ALIGN).with_best_order(max_order)
+}
@@ -0,0 +1,141 @@
+use super::*;
+use crate::test_support::CountingPageAllocator;
+use std::cell::Cell;
+use std::sync::Mutex;
+use std::thread;
+use std::vec::Vec;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+thread_local! {
+    static CPU: Cell<usize> = const { Cell::new(0) };
+}
+
+// one magazine per thread, which sets its CPU number in `CPU`
+struct ThreadCpus {
+    magazines: Vec<Mutex<Magazine>>,
+}
+
+impl ThreadCpus {
+    fn new(count: usize) -> Self {
+        Self { magazines: (0..count).map(|_| Mutex::new(Magazine::new())).collect() }
+    }
+}
+
+unsafe impl PerCpu for ThreadCpus {
+    fn with_current<R>(&self, f: impl FnOnce(&mut Magazine) -> R) -> R {
+        let cpu = CPU.with(Cell::get);
+        f(&mut self.magazines[cpu].lock().unwrap())
+    }
+
+    fn for_each(&mut self, mut f: impl FnMut(&mut Magazine)) {
+        for magazine in &mut self.magazines {
+            f(magazine.get_mut().unwrap());
+        }
+    }
+}
+
+#[repr(align(8))]
+struct Chunk {
+    data: [u64; 4],
+}
+
+fn new_allocator(cpus: usize) -> MagazineAllocator<Chunk, CountingPageAllocator, ThreadCpus> {
+    let slab = SlabAllocator::new(CountingPageAllocator::new());
+    MagazineAllocator::new(slab, ThreadCpus::new(cpus))
+}
+
+#[test]
+fn test_magazine_refill_and_spill_in_batches() {
+    let mut allocator = new_allocator(1);
+
+    let first = allocator.alloc().unwrap();
+    assert_eq!(allocator.stats().allocs, MAGAZINE_BATCH);
+
+    let mut ptrs = vec![first];
+    ptrs.extend((0..MAGAZINE_SIZE).map(|_| allocator.alloc().unwrap()));
+    assert_eq!(allocator.stats().allocs, MAGAZINE_BATCH * 3);
+    let in_magazine = MAGAZINE_BATCH * 3 - ptrs.len();
+
+    // frees fill the magazine before going to the slab, a batch at a time
+    let (to_fill, rest) = ptrs.split_at(MAGAZINE_SIZE - in_magazine);
+    for &ptr in to_fill {
+        unsafe { allocator.dealloc(ptr); }
+    }
+    assert_eq!(allocator.stats().frees, 0);
+    for &ptr in rest {
+        unsafe { allocator.dealloc(ptr); }
+    }
+    assert_eq!(allocator.stats().frees, MAGAZINE_BATCH);
+    assert_eq!(allocator.stats().live, MAGAZINE_BATCH * 2);
+
+    allocator.drain();
+    assert_eq!(allocator.stats().live, 0);
+    allocator.with_slab(|slab| {
+        slab.shrink();
+        assert!(slab.page_allocator.pages.is_empty());
+    });
+}
+
+#[test]
+fn test_magazine_free_on_another_cpu() {
+    let mut allocator = new_allocator(2);
+
+    let ptrs: Vec<_> = (0..100).map(|_| allocator.alloc().unwrap()).collect();
+    CPU.with(|cpu| cpu.set(1));
+    for ptr in ptrs {
+        unsafe { allocator.dealloc(ptr); }
+    }
+    CPU.with(|cpu| cpu.set(0));
+
+    allocator.drain();
+    assert_eq!(allocator.stats().live, 0);
+    assert_eq!(allocator.with_slab(|slab| slab.verify()), Ok(()));
+}
+
+// like test_slab_stress_many_pages, from several threads sharing the slab
+#[test]
+fn test_magazine_stress_threads() {
+    const THREADS: usize = 4;
+    const ROUNDS: usize = 20000;
+    let mut allocator = new_allocator(THREADS);
+
+    thread::scope(|scope| {
+        for cpu in 0..THREADS {
+            let allocator = &allocator;
+            scope.spawn(move || {
+                CPU.with(|x| x.set(cpu));
+                let mut rng = SmallRng::seed_from_u64(cpu as u64);
+                let mut live: Vec<(NonNull<Chunk>, u64)> = Vec::new();
+
+                for round in 0..ROUNDS {
+                    if live.is_empty() || rng.random_bool(0.55) {
+                        let ptr = allocator.alloc().unwrap();
+                        let tag = ((cpu as u64) << 32) | round as u64;
+                        unsafe { (*ptr.as_ptr()).data = [tag; 4]; }
+                        live.push((ptr, tag));
+                    }
+                    else {
+                        let (ptr, tag) = live.swap_remove(rng.random_range(0..live.len()));
+                        assert!(unsafe { (*ptr.as_ptr()).data } == [tag; 4], "object was overwritten");
+                        unsafe { allocator.dealloc(ptr); }
+                    }
+                }
+                for (ptr, tag) in live {
+                    assert!(unsafe { (*ptr.as_ptr()).data } == [tag; 4], "object was overwritten");
+                    unsafe { allocator.dealloc(ptr); }
+                }
+            });
+        }
+    });
+
+    allocator.drain();
+    let stats = allocator.stats();
+    assert_eq!(stats.live, 0);
+    assert_eq!(stats.allocs, stats.frees);
+    assert_eq!(allocator.with_slab(|slab| slab.verify()), Ok(()));
+    allocator.with_slab(|slab| {
+        slab.shrink();
+        assert!(slab.page_allocator.pages.is_empty());
+    });
+}
@@ -0,0 +1,192 @@
+use super::*;
+use crate::test_support::{CountingPageAllocator, NullPageAllocator, OrderPageAllocator};
+use core::alloc::Layout;
+#[cfg(feature = "redzone")]
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::vec::Vec;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+fn new_allocator() -> SizeClassAllocator<CountingPageAllocator> {
+    SizeClassAllocator::new(CountingPageAllocator::new())
+}
+
+fn layout(size: usize, align: usize) -> Layout {
+    Layout::from_size_align(size, align).unwrap()
+}
+
+#[test]
+fn test_size_class_index() {
+    type Allocator = SizeClassAllocator<CountingPageAllocator>;
+
+    assert_eq!(Allocator::class_index(layout(0, 1)), Some(0));
+    assert_eq!(Allocator::class_index(layout(16, 16)), Some(0));
+    assert_eq!(Allocator::class_index(layout(17, 1)), Some(1));
+    assert_eq!(Allocator::class_index(layout(96, 8)), Some(5));
+    assert_eq!(Allocator::class_index(layout(97, 8)), Some(6));
+    assert_eq!(Allocator::class_index(layout(2048, 8)), Some(SIZE_CLASSES.len() - 1));
+    assert_eq!(Allocator::class_index(layout(2049, 8)), None);
+    assert_eq!(Allocator::class_index(layout(8, 32)), None);
+}
+
+#[test]
+#[cfg(not(feature = "quarantine"))]
+fn test_size_class_every_class() {
+    let mut allocator = new_allocator();
+
+    for (class, &size) in SIZE_CLASSES.iter().enumerate() {
+        let layout = layout(size, 8);
+        let ptr = allocator.alloc(layout).unwrap();
+        assert_eq!(ptr.as_ptr() as usize % SIZE_CLASS_ALIGN, 0);
+        unsafe { core::ptr::write_bytes(ptr.as_ptr(), 0xab, size); }
+
+        let stats = allocator.stats(class);
+        assert_eq!(stats.object_size, size);
+        assert_eq!(stats.live, 1);
+        assert_eq!(stats.pages, 1);
+        assert!(stats.objects_per_page >= 1);
+
+        unsafe { allocator.dealloc(ptr, layout); }
+        assert_eq!(allocator.stats(class).pages, 0);
+    }
+    assert!(allocator.page_allocator().pages.is_empty());
+}
+
+#[test]
+fn test_size_class_unserved_layouts() {
+    let mut allocator = new_allocator();
+
+    assert!(allocator.alloc(layout(4096, 8)).is_none());
+    assert!(allocator.alloc(layout(64, 64)).is_none());
+    assert!(allocator.page_allocator().pages.is_empty());
+}
+
+#[test]
+fn test_size_class_random_sizes() {
+    let mut allocator = new_allocator();
+    let mut rng = SmallRng::seed_from_u64(7);
+    let mut live: Vec<(NonNull<u8>, Layout, u8)> = Vec::new();
+
+    for round in 0..4000 {
+        if live.is_empty() || rng.random_bool(0.6) {
+            let layout = layout(rng.random_range(1..=2048), 1 << rng.random_range(0..5));
+            let ptr = allocator.alloc(layout).unwrap();
+            let fill = round as u8;
+            unsafe { core::ptr::write_bytes(ptr.as_ptr(), fill, layout.size()); }
+            live.push((ptr, layout, fill));
+        }
+        else {
+            let (ptr, layout, fill) = live.swap_remove(rng.random_range(0..live.len()));
+            let data = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), layout.size()) };
+            assert!(data.iter().all(|&b| b == fill), "object was overwritten");
+            unsafe { allocator.dealloc(ptr, layout); }
+        }
+    }
+
+    let live_total: usize = (0..SIZE_CLASSES.len()).map(|class| allocator.stats(class).live).sum();
+    assert_eq!(live_total, live.len());
+
+    for (ptr, layout, _) in live.drain(..) {
+        unsafe { allocator.dealloc(ptr, layout); }
+    }
+    // flush the quarantine if any
+    allocator.shrink();
+    for class in 0..SIZE_CLASSES.len() {
+        let stats = allocator.stats(class);
+        assert_eq!(stats.live, 0);
+        assert_eq!(stats.allocs, stats.frees);
+        assert_eq!(stats.pages, 0);
+    }
+    assert!(allocator.page_allocator().pages.is_empty());
+}
+
+#[test]
+#[cfg(feature = "redzone")]
+fn test_size_class_redzone_overflow() {
+    let mut allocator = new_allocator();
+    let layout = layout(24, 8);
+
+    let ptr = allocator.alloc(layout).unwrap();
+    let result = catch_unwind(AssertUnwindSafe(|| unsafe {
+        *ptr.as_ptr().add(SIZE_CLASSES[1]) = 0xaa;
+        allocator.dealloc(ptr, layout);
+    }));
+    assert!(result.is_err(), "redzone overflow should panic");
+}
+
+#[test]
+fn test_size_class_failure_counter() {
+    let mut allocator = SizeClassAllocator::new(NullPageAllocator);
+    assert!(allocator.alloc(layout(100, 8)).is_none());
+
+    let stats = allocator.stats(6);
+    assert_eq!(stats.failures, 1);
+    assert_eq!(stats.allocs, 0);
+    assert_eq!(stats.live, 0);
+}
+
+#[test]
+fn test_size_class_objects_and_verify() {
+    let mut allocator = new_allocator();
+    let small = layout(20, 4);
+    let large = layout(1000, 8);
+
+    let a = allocator.alloc(small).unwrap();
+    let b = allocator.alloc(small).unwrap();
+    let c = allocator.alloc(large).unwrap();
+    assert_eq!(allocator.objects(1).count(), 2);
+    assert_eq!(allocator.objects(12).collect::<Vec<_>>(), [c]);
+    assert_eq!(allocator.verify(), Ok(()));
+
+    if REDZONE {
+        unsafe { *c.as_ptr().add(SIZE_CLASSES[12]) = 0; }
+        assert_eq!(allocator.verify(), Err(SlabCorruption::Redzone(c.as_ptr() as usize)));
+        unsafe { *c.as_ptr().add(SIZE_CLASSES[12]) = REDZONE_FILL; }
+    }
+
+    unsafe {
+        allocator.dealloc(a, small);
+        allocator.dealloc(b, small);
+        allocator.dealloc(c, large);
+    }
+    assert_eq!(allocator.objects(1).count(), 0);
+}
+
+#[test]
+fn test_size_class_shrink() {
+    let mut allocator = new_allocator();
+    allocator.set_empty_limit(1);
+
+    let small = layout(16, 8);
+    let large = layout(2000, 8);
+    let a = allocator.alloc(small).unwrap();
+    let b = allocator.alloc(large).unwrap();
+    unsafe {
+        allocator.dealloc(a, small);
+        allocator.dealloc(b, large);
+    }
+    assert_eq!(allocator.page_allocator().pages.len(), 2);
+
+    assert_eq!(allocator.shrink(), 2);
+    assert!(allocator.page_allocator().pages.is_empty());
+}
+
+#[test]
+fn test_size_class_multi_page_orders() {
+    let mut allocator = SizeClassAllocator::new(OrderPageAllocator::new());
+    let single = new_allocator();
+    let last = SIZE_CLASSES.len() - 1;
+    assert_eq!(allocator.stats(0).order, 0);
+    assert_eq!(single.stats(last).order, 0);
+    assert!(allocator.stats(last).order > 0);
+    assert!(allocator.stats(last).objects_per_page > single.stats(last).objects_per_page << allocator.stats(last).order);
+
+    let layout = layout(2000, 8);
+    let ptrs: Vec<_> = (0..20).map(|_| allocator.alloc(layout).unwrap()).collect();
+    assert_eq!(allocator.verify(), Ok(()));
+    for ptr in ptrs {
+        unsafe { allocator.dealloc(ptr, layout); }
+    }
+    allocator.shrink();
+    assert!(allocator.page_allocator().blocks.is_empty());
+}
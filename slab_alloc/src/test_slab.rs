@@ -1,4 +1,5 @@
 use super::*;
+use crate::test_support::{NullPageAllocator, OrderPageAllocator};
 use std::vec::Vec;
 use std::alloc::{alloc_zeroed, dealloc, Layout};
 use std::panic::{catch_unwind, AssertUnwindSafe};
@@ -710,6 +711,7 @@ fn test_slab_object_too_large_for_page_by_align() {
 */
 
 #[test]
+#[cfg(feature = "redzone")]
 fn test_slab_redzone_detection_on_overflow() {
     let page_allocator = MockPageAllocator::new();
     #[repr(align(8))]
@@ -730,6 +732,7 @@ fn test_slab_redzone_detection_on_overflow() {
 }
 
 #[test]
+#[cfg(feature = "redzone")]
 fn test_slab_redzone_detection_on_underflow() {
     let page_allocator = MockPageAllocator::new();
     #[repr(align(8))]
@@ -770,13 +773,14 @@ fn test_slab_redzone_integrity_on_normal_use() {
 }
 
 #[test]
+#[cfg(not(feature = "quarantine"))]
 fn test_slab_alloc_dealloc_full_page_cycle() {
     let page_allocator = MockPageAllocator::new();
     #[repr(align(8))]
     struct Chunk { _data: [u8; 32] }
     let mut slab: SlabAllocator<Chunk, _> = SlabAllocator::new(page_allocator);
 
-    let chunks_per_page = (PAGE_SIZE - SlotPage::<Chunk>::object_offset()) / SlotObject::<Chunk>::size_of();
+    let chunks_per_page = SlotLayout::of::<Chunk>().objects_per_page();
     let mut ptrs = Vec::with_capacity(chunks_per_page);
 
     slab.page_allocator.on_after_alloc = Some(|pa| {
@@ -800,13 +804,14 @@ fn test_slab_alloc_dealloc_full_page_cycle() {
 }
 
 #[test]
+#[cfg(not(feature = "quarantine"))]
 fn test_slab_alloc_dealloc_interleaved_pages() {
     let page_allocator = MockPageAllocator::new();
     #[repr(align(8))]
     struct Chunk { _data: [u8; 64] }
     let mut slab: SlabAllocator<Chunk, _> = SlabAllocator::new(page_allocator);
 
-    let chunks_per_page = (PAGE_SIZE - SlotPage::<Chunk>::object_offset()) / SlotObject::<Chunk>::size_of();
+    let chunks_per_page = SlotLayout::of::<Chunk>().objects_per_page();
     let mut ptrs = Vec::new();
 
     println!("Chunks per page: {}", chunks_per_page);
@@ -952,3 +957,454 @@ fn test_slab_alloc_dealloc_with_multiple_allocators() {
         unsafe { slab.dealloc(ptr); }
     }
 }
+
+#[test]
+#[cfg(not(feature = "quarantine"))]
+fn test_slab_stats_pages_and_objects() {
+    let page_allocator = MockPageAllocator::new();
+    #[repr(align(8))]
+    struct Chunk { _data: [u8; 64] }
+    let mut slab: SlabAllocator<Chunk, _> = SlabAllocator::new(page_allocator);
+
+    let per_page = SlotLayout::of::<Chunk>().objects_per_page();
+    let stats = slab.stats();
+    assert_eq!(stats.object_size, 64);
+    assert_eq!(stats.objects_per_page, per_page);
+    assert_eq!(stats.wasted_per_page, PAGE_SIZE - per_page * 64);
+    assert_eq!((stats.pages, stats.live, stats.allocs), (0, 0, 0));
+
+    // one full page and one partial page
+    let mut ptrs: Vec<_> = (0..per_page + 1).map(|_| slab.alloc().unwrap()).collect();
+    let stats = slab.stats();
+    assert_eq!(stats.pages, 2);
+    assert_eq!(stats.full_pages, 1);
+    assert_eq!(stats.partial_pages, 1);
+    assert_eq!(stats.live, per_page + 1);
+    assert_eq!(stats.allocs, per_page + 1);
+
+    // freeing from the full page makes both pages partial
+    unsafe { slab.dealloc(ptrs.remove(0)); }
+    let stats = slab.stats();
+    assert_eq!((stats.full_pages, stats.partial_pages), (0, 2));
+    assert_eq!(stats.frees, 1);
+
+    for ptr in ptrs {
+        unsafe { slab.dealloc(ptr); }
+    }
+    let stats = slab.stats();
+    assert_eq!((stats.pages, stats.full_pages, stats.partial_pages), (0, 0, 0));
+    assert_eq!(stats.live, 0);
+    assert_eq!(stats.allocs, stats.frees);
+}
+
+#[test]
+#[cfg(not(feature = "quarantine"))]
+fn test_slab_stats_one_object_per_page() {
+    let page_allocator = MockPageAllocator::new();
+    struct Chunk { _data: [u8; 3000] }
+    let mut slab: SlabAllocator<Chunk, _> = SlabAllocator::new(page_allocator);
+
+    assert_eq!(slab.stats().objects_per_page, 1);
+
+    let ptr = slab.alloc().unwrap();
+    let stats = slab.stats();
+    assert_eq!((stats.pages, stats.full_pages, stats.partial_pages), (1, 1, 0));
+
+    unsafe { slab.dealloc(ptr); }
+    let stats = slab.stats();
+    assert_eq!((stats.pages, stats.full_pages, stats.partial_pages), (0, 0, 0));
+}
+
+#[test]
+fn test_slab_stats_failures() {
+    let mut slab: SlabAllocator<u64, _> = SlabAllocator::new(NullPageAllocator);
+    assert!(slab.alloc().is_none());
+    assert!(slab.alloc().is_none());
+
+    let stats = slab.stats();
+    assert_eq!(stats.failures, 2);
+    assert_eq!(stats.allocs, 0);
+}
+
+#[test]
+fn test_slab_objects_iteration() {
+    let page_allocator = MockPageAllocator::new();
+    #[repr(align(8))]
+    struct Chunk { data: u64, _pad: [u8; 56] }
+    let mut slab: SlabAllocator<Chunk, _> = SlabAllocator::new(page_allocator);
+
+    assert_eq!(slab.objects().count(), 0);
+
+    // only full pages
+    let per_page = SlotLayout::of::<Chunk>().objects_per_page();
+    let mut ptrs: Vec<_> = (0..per_page).map(|_| slab.alloc().unwrap()).collect();
+    assert_eq!(slab.stats().partial_pages, 0);
+    assert_eq!(slab.objects().count(), per_page);
+
+    // full and partial pages, with holes
+    ptrs.extend((0..per_page * 2).map(|_| slab.alloc().unwrap()));
+    for (idx, ptr) in ptrs.iter().enumerate() {
+        unsafe { (*ptr.as_ptr()).data = idx as u64; }
+    }
+    let mut freed = Vec::new();
+    for idx in (0..ptrs.len()).step_by(3) {
+        unsafe { slab.dealloc(ptrs[idx]); }
+        freed.push(ptrs[idx]);
+    }
+    ptrs.retain(|x| !freed.contains(x));
+
+    let mut found: Vec<_> = slab.objects().collect();
+    found.sort();
+    let mut expected = ptrs.clone();
+    expected.sort();
+    assert_eq!(found, expected);
+    assert!(found.iter().all(|x| unsafe { (*x.as_ptr()).data } % 3 != 0));
+
+    for ptr in ptrs {
+        unsafe { slab.dealloc(ptr); }
+    }
+    assert_eq!(slab.objects().count(), 0);
+}
+
+#[test]
+fn test_slab_verify() {
+    let page_allocator = MockPageAllocator::new();
+    #[repr(align(8))]
+    struct Chunk { _data: [u8; 32] }
+    let mut slab: SlabAllocator<Chunk, _> = SlabAllocator::new(page_allocator);
+
+    let ptrs: Vec<_> = (0..8).map(|_| slab.alloc().unwrap()).collect();
+    unsafe { slab.dealloc(ptrs[1]); }
+    assert_eq!(slab.verify(), Ok(()));
+
+    // use after free is found without allocating the slot again
+    if POISON {
+        let freed = ptrs[1].as_ptr() as *mut u8;
+        unsafe { *freed.add(4) = 0; }
+        assert_eq!(slab.verify(), Err(SlabCorruption::WrittenAfterFree(freed as usize)));
+        unsafe { *freed.add(4) = UNUSED_FILL; }
+        assert_eq!(slab.verify(), Ok(()));
+    }
+
+    // overflow of a live object is found without freeing it
+    if REDZONE {
+        let live = ptrs[2].as_ptr() as *mut u8;
+        unsafe { *live.add(size_of::<Chunk>()) = 0; }
+        assert_eq!(slab.verify(), Err(SlabCorruption::Redzone(live as usize)));
+        unsafe { *live.add(size_of::<Chunk>()) = REDZONE_FILL; }
+        assert_eq!(slab.verify(), Ok(()));
+    }
+}
+
+// allocate and free one object right after a page is filled, over and over
+#[cfg(not(feature = "quarantine"))]
+fn thrash_page_boundary<T>(slab: &mut SlabAllocator<T, MockPageAllocator>, rounds: usize) -> Vec<NonNull<T>> {
+    let per_page = slab.stats().objects_per_page;
+    let ptrs: Vec<_> = (0..per_page).map(|_| slab.alloc().unwrap()).collect();
+    for _ in 0..rounds {
+        let ptr = slab.alloc().unwrap();
+        unsafe { slab.dealloc(ptr); }
+    }
+    ptrs
+}
+
+#[test]
+#[cfg(not(feature = "quarantine"))]
+fn test_slab_page_boundary_thrash_without_cache() {
+    let page_allocator = MockPageAllocator::new();
+    #[repr(align(8))]
+    struct Chunk { _data: [u8; 64] }
+    let mut slab: SlabAllocator<Chunk, _> = SlabAllocator::new(page_allocator);
+
+    let ptrs = thrash_page_boundary(&mut slab, 10);
+    // every round takes a new page and returns it
+    assert_eq!(slab.page_allocator.deallocated.len(), 10);
+
+    for ptr in ptrs {
+        unsafe { slab.dealloc(ptr); }
+    }
+}
+
+#[test]
+#[cfg(not(feature = "quarantine"))]
+fn test_slab_page_boundary_thrash_with_cache() {
+    let page_allocator = MockPageAllocator::new();
+    #[repr(align(8))]
+    struct Chunk { _data: [u8; 64] }
+    let mut slab: SlabAllocator<Chunk, _> = SlabAllocator::new(page_allocator);
+    slab.set_empty_limit(1);
+
+    slab.page_allocator.on_before_dealloc = Some(|_| {
+        panic!("the empty page should be kept");
+    });
+    let ptrs = thrash_page_boundary(&mut slab, 10);
+    assert_eq!(slab.page_allocator.pages.len(), 2);
+
+    let stats = slab.stats();
+    assert_eq!((stats.pages, stats.full_pages, stats.partial_pages, stats.empty_pages), (2, 1, 0, 1));
+    assert_eq!(slab.verify(), Ok(()));
+
+    slab.page_allocator.on_before_dealloc = None;
+    for ptr in ptrs {
+        unsafe { slab.dealloc(ptr); }
+    }
+    // the limit still holds when more pages become empty
+    assert_eq!(slab.stats().empty_pages, 1);
+    assert_eq!(slab.page_allocator.pages.len(), 1);
+
+    assert_eq!(slab.shrink(), 1);
+    assert_eq!(slab.shrink(), 0);
+    assert_eq!(slab.stats().pages, 0);
+    assert!(slab.page_allocator.pages.is_empty());
+}
+
+#[test]
+#[cfg(not(feature = "quarantine"))]
+fn test_slab_empty_pages_are_reused() {
+    let page_allocator = MockPageAllocator::new();
+    #[repr(align(8))]
+    struct Chunk { _data: [u8; 256] }
+    let mut slab: SlabAllocator<Chunk, _> = SlabAllocator::new(page_allocator);
+    slab.set_empty_limit(4);
+
+    let per_page = slab.stats().objects_per_page;
+    let ptrs: Vec<_> = (0..per_page * 3).map(|_| slab.alloc().unwrap()).collect();
+    for ptr in ptrs {
+        unsafe { slab.dealloc(ptr); }
+    }
+    assert_eq!(slab.stats().empty_pages, 3);
+    assert_eq!(slab.objects().count(), 0);
+    assert_eq!(slab.verify(), Ok(()));
+
+    slab.page_allocator.on_after_alloc = Some(|_| {
+        panic!("empty pages should be used first");
+    });
+    let ptrs: Vec<_> = (0..per_page * 3).map(|_| slab.alloc().unwrap()).collect();
+    assert_eq!(slab.stats().empty_pages, 0);
+    assert_eq!(slab.stats().full_pages, 3);
+
+    slab.page_allocator.on_after_alloc = None;
+    for ptr in ptrs {
+        unsafe { slab.dealloc(ptr); }
+    }
+
+    // lowering the limit releases the extra pages
+    slab.set_empty_limit(1);
+    assert_eq!(slab.stats().empty_pages, 1);
+    assert_eq!(slab.page_allocator.pages.len(), 1);
+}
+
+#[test]
+#[cfg(feature = "quarantine")]
+fn test_slab_quarantine_delays_reuse() {
+    let page_allocator = MockPageAllocator::new();
+    #[repr(align(8))]
+    struct Chunk { _data: [u8; 32] }
+    let mut slab: SlabAllocator<Chunk, _> = SlabAllocator::new(page_allocator);
+
+    let first = slab.alloc().unwrap();
+    unsafe { slab.dealloc(first); }
+    assert_eq!(slab.stats().quarantined, 1);
+    assert_eq!(slab.stats().live, 0);
+    assert_eq!(slab.objects().count(), 0);
+    assert_eq!(slab.verify(), Ok(()));
+
+    // the freed object comes back only after QUARANTINE_SIZE other frees
+    let mut ptrs = Vec::new();
+    for _ in 0..QUARANTINE_SIZE {
+        let ptr = slab.alloc().unwrap();
+        assert_ne!(ptr, first);
+        ptrs.push(ptr);
+    }
+    for ptr in ptrs {
+        unsafe { slab.dealloc(ptr); }
+    }
+    assert_eq!(slab.stats().quarantined, QUARANTINE_SIZE);
+    assert_eq!(slab.alloc().unwrap(), first);
+    unsafe { slab.dealloc(first); }
+
+    // shrink flushes the quarantine before releasing the empty pages
+    assert_eq!(slab.shrink(), 0);
+    assert_eq!(slab.stats().quarantined, 0);
+    assert_eq!(slab.stats().pages, 0);
+    assert!(slab.page_allocator.pages.is_empty());
+}
+
+#[test]
+#[cfg(feature = "quarantine")]
+fn test_slab_quarantine_write_after_free() {
+    let page_allocator = MockPageAllocator::new();
+    #[repr(align(8))]
+    struct Chunk { _data: [u8; 32] }
+    let mut slab: SlabAllocator<Chunk, _> = SlabAllocator::new(page_allocator);
+    slab.set_name("chunk");
+
+    let ptrs: Vec<_> = (0..QUARANTINE_SIZE + 1).map(|_| slab.alloc().unwrap()).collect();
+    unsafe { slab.dealloc(ptrs[0]); }
+    unsafe { *(ptrs[0].as_ptr() as *mut u8).add(8) = 0x42; }
+    assert_eq!(slab.verify(), Err(SlabCorruption::WrittenAfterFree(ptrs[0].as_ptr() as usize)));
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        for &ptr in &ptrs[1..] {
+            unsafe { slab.dealloc(ptr); }
+        }
+    }));
+    let message = *result.unwrap_err().downcast::<String>().unwrap();
+    assert!(message.starts_with("object is written after free"), "{}", message);
+    assert!(message.contains(&format!("{:p}", ptrs[0].as_ptr())), "{}", message);
+    assert!(message.ends_with("in chunk"), "{}", message);
+}
+
+#[test]
+fn test_slab_name_in_panic_message() {
+    let page_allocator = MockPageAllocator::new();
+    #[repr(align(8))]
+    struct Chunk { _data: [u8; 32] }
+    let mut slab: SlabAllocator<Chunk, _> = SlabAllocator::new(page_allocator);
+
+    let ptr = slab.alloc().unwrap();
+    unsafe { slab.dealloc(ptr); }
+    let result = catch_unwind(AssertUnwindSafe(|| unsafe {
+        slab.dealloc(ptr);
+    }));
+    let message = *result.unwrap_err().downcast::<String>().unwrap();
+    assert!(message.starts_with("try to deallocate an object that is not allocated"), "{}", message);
+    assert!(message.ends_with("test_slab_name_in_panic_message::Chunk"), "{}", message);
+}
+
+#[test]
+fn test_slab_order_minimises_waste() {
+    #[repr(align(8))]
+    struct Small { _data: [u8; 32] }
+    #[repr(align(8))]
+    struct Medium { _data: [u8; 2100] }
+
+    let small: SlabAllocator<Small, _> = SlabAllocator::new(OrderPageAllocator::new());
+    assert_eq!(small.stats().order, 0);
+
+    // without higher orders, half of the page is wasted
+    let single: SlabAllocator<Medium, _> = SlabAllocator::new(MockPageAllocator::new());
+    assert_eq!(single.stats().order, 0);
+    assert_eq!(single.stats().objects_per_page, 1);
+
+    let multi: SlabAllocator<Medium, _> = SlabAllocator::new(OrderPageAllocator::new());
+    let stats = multi.stats();
+    assert!(stats.order > 0);
+    let page_size = PAGE_SIZE << stats.order;
+    let tail = page_size - SlotLayout::of::<Medium>().object_offset() - stats.objects_per_page * stats.slot_size;
+    assert!(tail * 16 <= page_size);
+    assert!(stats.wasted_per_page * PAGE_SIZE < single.stats().wasted_per_page * page_size);
+}
+
+#[test]
+fn test_slab_multi_page_objects() {
+    #[repr(align(16))]
+    struct Large { data: [u64; 700] }
+    let mut slab: SlabAllocator<Large, _> = SlabAllocator::new(OrderPageAllocator::new());
+    let stats = slab.stats();
+    assert!(size_of::<Large>() > PAGE_SIZE);
+    assert!(stats.order > 0);
+    assert!(stats.objects_per_page > 1);
+
+    let count = stats.objects_per_page * 3 + 1;
+    let mut ptrs: Vec<_> = (0..count).map(|_| slab.alloc().unwrap()).collect();
+    for (idx, ptr) in ptrs.iter().enumerate() {
+        assert_eq!(ptr.as_ptr() as usize % 16, 0);
+        unsafe { (*ptr.as_ptr()).data = [idx as u64; 700]; }
+    }
+    assert_eq!(slab.stats().pages, 4);
+    assert_eq!(slab.stats().full_pages, 3);
+    assert_eq!(slab.verify(), Ok(()));
+
+    // the pages are not aligned in their size, so the headers cannot be found by masking
+    let page_size = PAGE_SIZE << stats.order;
+    assert!(slab.page_allocator.blocks.iter().any(|&(p, _, _)| !(p.as_ptr() as usize).is_multiple_of(page_size)));
+
+    for (idx, ptr) in ptrs.iter().enumerate() {
+        assert!(unsafe { (*ptr.as_ptr()).data.iter().all(|&x| x == idx as u64) });
+    }
+
+    let mut rng = SmallRng::seed_from_u64(15);
+    ptrs.shuffle(&mut rng);
+    for ptr in ptrs {
+        unsafe { slab.dealloc(ptr); }
+    }
+    slab.shrink();
+    assert_eq!(slab.stats().pages, 0);
+    assert!(slab.page_allocator.blocks.is_empty());
+}
+
+#[test]
+fn test_slab_ctor_dtor() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+    static DESTRUCTED: AtomicUsize = AtomicUsize::new(0);
+
+    struct WaitQueue { magic: u64, count: u32 }
+    fn ctor(ptr: NonNull<WaitQueue>) {
+        unsafe { ptr.as_ptr().write(WaitQueue { magic: 0x5741_4954, count: 0 }); }
+        CONSTRUCTED.fetch_add(1, Ordering::Relaxed);
+    }
+    fn dtor(ptr: NonNull<WaitQueue>) {
+        assert_eq!(unsafe { (*ptr.as_ptr()).magic }, 0x5741_4954);
+        DESTRUCTED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let mut slab = SlabAllocator::with_ctor(MockPageAllocator::new(), ctor, Some(dtor));
+    slab.set_empty_limit(1);
+    let per_page = slab.stats().objects_per_page;
+
+    let a = slab.alloc().unwrap();
+    assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), per_page);
+    assert_eq!(unsafe { (*a.as_ptr()).magic }, 0x5741_4954);
+
+    // freed objects are neither poisoned nor zeroed when handed out again
+    unsafe { (*a.as_ptr()).count = 7; }
+    unsafe { slab.dealloc(a); }
+    assert_eq!(slab.verify(), Ok(()));
+    if !QUARANTINE {
+        let b = slab.alloc().unwrap();
+        assert_eq!(b, a);
+        assert_eq!(unsafe { (*b.as_ptr()).count }, 7);
+        unsafe { slab.dealloc(b); }
+    }
+
+    let ptrs: Vec<_> = (0..per_page * 2).map(|_| slab.alloc().unwrap()).collect();
+    assert!(ptrs.iter().all(|x| unsafe { (*x.as_ptr()).magic } == 0x5741_4954));
+    assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), per_page * slab.stats().pages);
+    assert_eq!(DESTRUCTED.load(Ordering::Relaxed), 0);
+    for ptr in ptrs {
+        unsafe { slab.dealloc(ptr); }
+    }
+
+    slab.shrink();
+    assert_eq!(DESTRUCTED.load(Ordering::Relaxed), CONSTRUCTED.load(Ordering::Relaxed));
+    assert!(slab.page_allocator.pages.is_empty());
+}
+
+#[test]
+fn test_slab_cache_coloring() {
+    #[repr(align(8))]
+    struct Chunk { _data: [u8; 200] }
+    let mut slab: SlabAllocator<Chunk, _> = SlabAllocator::new(MockPageAllocator::new());
+
+    let layout = SlotLayout::of::<Chunk>();
+    let colors = layout.colors();
+    assert!(colors > 1);
+
+    // the first object of each page is shifted by one more cache line, wrapping around
+    let per_page = slab.stats().objects_per_page;
+    let ptrs: Vec<_> = (0..per_page * (colors + 1)).map(|_| slab.alloc().unwrap()).collect();
+    for (page, chunk) in ptrs.chunks(per_page).enumerate() {
+        let offset = chunk[0].as_ptr() as usize % PAGE_SIZE;
+        let expected = layout.object_offset() + layout.payload_offset() + (page % colors) * CACHE_LINE_SIZE;
+        assert_eq!(offset, expected);
+        assert!(chunk.iter().all(|x| x.as_ptr() as usize / PAGE_SIZE == chunk[0].as_ptr() as usize / PAGE_SIZE));
+    }
+    assert_eq!(slab.objects().count(), ptrs.len());
+    assert_eq!(slab.verify(), Ok(()));
+
+    for ptr in ptrs {
+        unsafe { slab.dealloc(ptr); }
+    }
+}
@@ -0,0 +1,92 @@
+use super::*;
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::vec::Vec;
+
+// page allocator on the host heap, keeping the live pages
+pub(crate) struct CountingPageAllocator {
+    pub(crate) pages: Vec<NonNull<[u8; PAGE_SIZE]>>,
+}
+
+impl CountingPageAllocator {
+    pub(crate) fn new() -> Self {
+        Self { pages: Vec::new() }
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()
+    }
+}
+
+unsafe impl PageAllocator for CountingPageAllocator {
+    fn allocate(&mut self) -> Option<NonNull<[u8; PAGE_SIZE]>> {
+        let page = unsafe { alloc_zeroed(Self::layout()) as *mut [u8; PAGE_SIZE] };
+        let ptr = NonNull::new(page).unwrap();
+        self.pages.push(ptr);
+        Some(ptr)
+    }
+
+    unsafe fn deallocate(&mut self, ptr: NonNull<[u8; PAGE_SIZE]>) {
+        let index = self.pages.iter().position(|&p| p == ptr).unwrap();
+        self.pages.remove(index);
+        unsafe { dealloc(ptr.as_ptr() as *mut u8, Self::layout()); }
+    }
+}
+
+// page allocator which is always out of memory
+pub(crate) struct NullPageAllocator;
+
+unsafe impl PageAllocator for NullPageAllocator {
+    fn allocate(&mut self) -> Option<NonNull<[u8; PAGE_SIZE]>> {
+        None
+    }
+
+    unsafe fn deallocate(&mut self, _ptr: NonNull<[u8; PAGE_SIZE]>) {}
+}
+
+// serves up to MAX_SLAB_ORDER, placing blocks off the alignment of their size when it can
+pub(crate) struct OrderPageAllocator {
+    pub(crate) blocks: Vec<(NonNull<u8>, *mut u8, Layout)>,
+}
+
+impl OrderPageAllocator {
+    pub(crate) fn new() -> Self {
+        Self { blocks: Vec::new() }
+    }
+}
+
+unsafe impl PageAllocator for OrderPageAllocator {
+    const MAX_ORDER: u32 = MAX_SLAB_ORDER;
+
+    fn allocate(&mut self) -> Option<NonNull<[u8; PAGE_SIZE]>> {
+        self.allocate_order(0).map(NonNull::cast)
+    }
+
+    unsafe fn deallocate(&mut self, ptr: NonNull<[u8; PAGE_SIZE]>) {
+        unsafe { self.deallocate_order(ptr.cast(), 0); }
+    }
+
+    fn allocate_order(&mut self, order: u32) -> Option<NonNull<u8>> {
+        let size = PAGE_SIZE << order;
+        let layout = Layout::from_size_align(size + PAGE_SIZE, PAGE_SIZE).unwrap();
+        let base = unsafe { alloc_zeroed(layout) };
+        let addr = if (base as usize).is_multiple_of(size) { base.wrapping_add(PAGE_SIZE) } else { base };
+        let ptr = NonNull::new(addr).unwrap();
+        self.blocks.push((ptr, base, layout));
+        Some(ptr)
+    }
+
+    unsafe fn deallocate_order(&mut self, ptr: NonNull<u8>, order: u32) {
+        let index = self.blocks.iter().position(|&(p, _, _)| p == ptr).unwrap();
+        let (_, base, layout) = self.blocks.remove(index);
+        assert_eq!(layout.size(), (PAGE_SIZE << order) + PAGE_SIZE);
+        unsafe { dealloc(base, layout); }
+    }
+}
+
+impl Drop for OrderPageAllocator {
+    fn drop(&mut self) {
+        for &(_, base, layout) in &self.blocks {
+            unsafe { dealloc(base, layout); }
+        }
+    }
+}